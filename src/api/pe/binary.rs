@@ -0,0 +1,103 @@
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+use super::storage::StorageKey;
+
+/// 二进制帧格式:
+/// | 1字节 数据类型 | 2字节 标识长度(大端) | 标识(UTF-8) | 数据 |
+/// 标识为玩家名或区块名, 规则见`StorageKey`; 压缩文本帧的标识为空
+const HEADER_LEN: usize = 3;
+
+/// 二进制帧的数据类型
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BinaryKind {
+    /// 玩家nbt
+    PlayerNbt = 0x01,
+    /// 世界区块
+    WorldChunk = 0x02,
+    /// deflate压缩的文本帧
+    CompressedText = 0x10,
+}
+
+impl BinaryKind {
+    fn from_u8(kind: u8) -> Option<BinaryKind> {
+        match kind {
+            0x01 => Some(BinaryKind::PlayerNbt),
+            0x02 => Some(BinaryKind::WorldChunk),
+            0x10 => Some(BinaryKind::CompressedText),
+            _ => None,
+        }
+    }
+
+    /// 数据的存储键, 标识非法时返回错误
+    pub fn storage_key(self, key: &str) -> Result<StorageKey, &'static str> {
+        match self {
+            BinaryKind::PlayerNbt => StorageKey::player(key),
+            BinaryKind::WorldChunk => StorageKey::chunk(key),
+            BinaryKind::CompressedText => Err("压缩文本帧没有存储数据"),
+        }
+    }
+}
+
+/// 解析后的二进制帧
+#[derive(Debug)]
+pub struct BinaryFrame<'a> {
+    pub kind: BinaryKind,
+    pub key: &'a str,
+    pub payload: &'a [u8],
+}
+
+/// 解析二进制帧
+pub fn decode(bytes: &[u8]) -> Result<BinaryFrame<'_>, &'static str> {
+    if bytes.len() < HEADER_LEN {
+        return Err("二进制帧头不完整");
+    }
+    let kind = BinaryKind::from_u8(bytes[0]).ok_or("未知的二进制数据类型")?;
+    let key_len = u16::from_be_bytes([bytes[1], bytes[2]]) as usize;
+    let key = bytes
+        .get(HEADER_LEN..HEADER_LEN + key_len)
+        .ok_or("二进制帧标识不完整")?;
+    let key = std::str::from_utf8(key).map_err(|_| "二进制帧标识不是UTF-8")?;
+    if kind == BinaryKind::CompressedText && !key.is_empty() {
+        return Err("二进制帧标识非法");
+    }
+    Ok(BinaryFrame {
+        kind,
+        key,
+        payload: &bytes[HEADER_LEN + key_len..],
+    })
+}
+
+/// 编码二进制帧
+pub fn encode(kind: BinaryKind, key: &str, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + key.len() + payload.len());
+    bytes.push(kind as u8);
+    bytes.extend_from_slice(&(key.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(key.as_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// 压缩文本为压缩文本帧
+pub fn compress_text(text: &str) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    let _ = encoder.write_all(text.as_bytes());
+    let payload = encoder.finish().unwrap_or_default();
+    encode(BinaryKind::CompressedText, "", &payload)
+}
+
+/// 解压压缩文本帧的数据, 解压后超过`max_size`字节时返回错误
+pub fn decompress_text(payload: &[u8], max_size: usize) -> Result<String, &'static str> {
+    let mut text = String::new();
+    DeflateDecoder::new(payload)
+        .take(max_size as u64 + 1)
+        .read_to_string(&mut text)
+        .map_err(|_| "压缩数据解压失败")?;
+    if text.len() > max_size {
+        return Err("解压后的消息超过大小限制");
+    }
+    Ok(text)
+}
@@ -1,78 +1,555 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use actix::prelude::*;
-use rand::{rngs::ThreadRng, Rng};
+use serde::Serialize;
 
+use crate::{
+    config::{DeliveryConfig, ServerConfig},
+    sql::chat_history::{ChatHistoryEntry, ChatHistorySqlite},
+};
+
+use super::{
+    command::{drop_server_commands, PendingCommand},
+    delivery::{Enqueue, Outbox, Queued, SendQueue},
+    moderation::Moderator,
+    protocol::{ServerFrame, FRAMED_PROTOCOL},
+};
+
+/// 默认频道, 所有会话连接后自动加入
+pub const DEFAULT_CHANNEL: &str = "global";
+
+/// 检查频道名是否合法: 1-32位字母、数字、`-`、`_`
+pub fn is_valid_channel(channel: &str) -> bool {
+    !channel.is_empty()
+        && channel.len() <= 32
+        && channel
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// 通知会话取出发送队列中的消息
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct Message(pub String);
+pub struct Flush;
+
+/// 通知会话断开连接
+/// replaced: 是否因同名服务端接管而断开, 接管时不清理该服务端的玩家
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Kick {
+    pub reason: String,
+    pub replaced: bool,
+}
+
+/// 会话消息计数, 由会话和聊天服务器共同更新
+/// received: 收到客户端的帧数
+/// sent: 发送给客户端的消息数
+#[derive(Debug, Default)]
+pub struct SessionStats {
+    pub received: AtomicU64,
+    pub sent: AtomicU64,
+}
+
+/// 已连接的会话
+#[derive(Debug)]
+struct Session {
+    addr: Recipient<Flush>,
+    kick: Recipient<Kick>,
+    /// 服务端名
+    name: String,
+    /// 连接时间(unix秒)
+    connected_at: i64,
+    /// 客户端地址
+    remote_addr: Option<String>,
+    /// 协议版本
+    protocol: u32,
+    stats: Arc<SessionStats>,
+    /// 待发送的消息, 与会话共享
+    queue: Arc<SendQueue>,
+    /// 未确认的消息, 仅开启消息确认的会话存在
+    outbox: Option<Outbox>,
+}
+
+impl Session {
+    /// 将消息加入发送队列, 队列已满且配置为断开时返回false
+    fn send(&self, text: String, config: &DeliveryConfig) -> bool {
+        match self.queue.push(text, config) {
+            Queued::Wake => {
+                self.addr.do_send(Flush);
+                true
+            }
+            Queued::Pending => true,
+            Queued::Overflow => false,
+        }
+    }
+}
+
+/// 会话信息, 用于管理接口
+#[derive(Serialize, Debug)]
+pub struct SessionInfo {
+    pub id: usize,
+    pub server_name: String,
+    pub connected_at: i64,
+    pub remote_addr: Option<String>,
+    pub protocol: u32,
+    pub channels: Vec<String>,
+    pub received: u64,
+    pub sent: u64,
+    /// 未发出的消息数
+    pub queued: usize,
+    /// 未确认的消息数, 未开启消息确认时为None
+    pub unacked: Option<usize>,
+}
 
 #[derive(Debug)]
 pub struct ChatServer {
-    sessions: HashMap<usize, Recipient<Message>>,
-    rng: ThreadRng,
+    sessions: HashMap<usize, Session>,
+    /// 服务端名 -> 会话id
+    names: HashMap<String, usize>,
+    /// 频道名 -> 成员会话id
+    channels: HashMap<String, HashSet<usize>>,
+    /// 频道名 -> 最近的聊天记录
+    history: HashMap<String, VecDeque<ChatHistoryEntry>>,
+    /// 每个频道保留的聊天记录条数
+    history_size: usize,
+    /// 聊天记录持久化, 未开启时为None
+    history_store: Option<ChatHistorySqlite>,
+    /// 聊天审核
+    pub(super) moderator: Moderator,
+    /// 消息确认投递配置
+    delivery: DeliveryConfig,
+    /// 下一个分配的会话id, 从1开始
+    next_id: usize,
+    /// 等待结果的远程命令
+    pub(super) pending_commands: HashMap<u64, PendingCommand>,
+    pub(super) next_command_id: u64,
 }
 
 impl Actor for ChatServer {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(Duration::from_millis(500), |act, _| act.resend_unacked());
+    }
 }
 
 impl ChatServer {
-    pub fn new() -> ChatServer {
+    pub fn new(config: &ServerConfig) -> ChatServer {
+        let mut channels = HashMap::new();
+        channels.insert(DEFAULT_CHANNEL.to_owned(), HashSet::new());
+        // 分配序号需要频道的最后一条记录, 内存和数据库至少保留1条
+        let history_size = config.chat_history_size.max(1);
+        let history_store = if config.chat_history_persist {
+            match ChatHistorySqlite::init(history_size) {
+                Ok(store) => Some(store),
+                Err(err) => {
+                    log::error!("聊天记录数据库初始化失败: {}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let mut history: HashMap<String, VecDeque<ChatHistoryEntry>> = HashMap::new();
+        if let Some(store) = &history_store {
+            match store.get_recent() {
+                Ok(entries) => {
+                    for entry in entries {
+                        history
+                            .entry(entry.channel.clone())
+                            .or_default()
+                            .push_back(entry);
+                    }
+                }
+                Err(err) => log::error!("聊天记录读取失败: {}", err),
+            }
+        }
         ChatServer {
             sessions: HashMap::new(),
-            rng: rand::thread_rng(),
+            names: HashMap::new(),
+            channels,
+            history,
+            history_size,
+            history_store,
+            moderator: Moderator::new(&config.moderation),
+            delivery: config.delivery.clone(),
+            next_id: 1,
+            pending_commands: HashMap::new(),
+            next_command_id: 0,
         }
     }
 }
 
 impl ChatServer {
-    fn send_message(&self, message: &str, skip_id: usize) {
-        for (id, _) in &self.sessions {
-            if *id != skip_id {
-                if let Some(addr) = self.sessions.get(&id) {
-                    addr.do_send(Message(message.to_owned()));
+    /// 记录一条频道消息, 返回分配的序号
+    /// 没有历史记录的频道以当前毫秒时间戳作为起始序号, 保证重启后序号仍然递增
+    fn record_message(&mut self, channel: &str, message: &str) -> u64 {
+        let now = chrono::Local::now();
+        let history = self.history.entry(channel.to_owned()).or_default();
+        let seq = history
+            .back()
+            .map_or(now.timestamp_millis() as u64, |entry| entry.seq + 1);
+        let entry = ChatHistoryEntry {
+            seq,
+            channel: channel.to_owned(),
+            msg: message.to_owned(),
+            time: now.timestamp(),
+        };
+        if let Some(store) = &self.history_store {
+            if let Err(err) = store.add_message(&entry) {
+                log::error!("聊天记录保存失败: {}", err);
+            }
+        }
+        history.push_back(entry);
+        while history.len() > self.history_size {
+            history.pop_front();
+        }
+        seq
+    }
+
+    /// 记录消息并发送给频道内除`skip_id`外的所有成员
+    fn send_message(&mut self, channel: &str, message: &str, skip_id: Option<usize>) {
+        let seq = self.record_message(channel, message);
+        let Some(members) = self.channels.get(channel) else {
+            return;
+        };
+        let text = ServerFrame::Chat {
+            channel,
+            seq,
+            msg: message,
+        }
+        .to_text();
+        let members: Vec<usize> = members.iter().copied().collect();
+        for id in members {
+            if Some(id) != skip_id {
+                self.deliver_chat(id, &text, message);
+            }
+        }
+    }
+
+    /// 会话是否使用JSON帧协议
+    pub(super) fn is_framed(&self, id: usize) -> bool {
+        self.sessions
+            .get(&id)
+            .is_some_and(|session| session.protocol == FRAMED_PROTOCOL)
+    }
+
+    /// 向会话发送一条聊天消息, 旧协议的会话只收到消息原文
+    fn deliver_chat(&mut self, id: usize, frame: &str, message: &str) {
+        if self.is_framed(id) {
+            self.deliver(id, frame);
+        } else {
+            self.deliver(id, message);
+        }
+    }
+
+    /// 向会话发送一条文本帧, 会话不存在时返回false
+    /// 开启消息确认的会话会为消息分配id并等待确认
+    /// 未确认或未发出的消息超过队列长度时按配置丢弃旧消息或断开会话
+    pub(super) fn deliver(&mut self, id: usize, text: &str) -> bool {
+        let Some(session) = self.sessions.get_mut(&id) else {
+            return false;
+        };
+        session.stats.sent.fetch_add(1, Ordering::Relaxed);
+        let sent = match session.outbox.as_mut() {
+            None => session.send(text.to_owned(), &self.delivery),
+            Some(outbox) => match outbox.enqueue(text, &self.delivery) {
+                Enqueue::Send(text) => session.send(text, &self.delivery),
+                Enqueue::Overflow => false,
+            },
+        };
+        if !sent {
+            self.overflow(id);
+        }
+        true
+    }
+
+    /// 发送队列已满, 断开会话
+    fn overflow(&mut self, id: usize) {
+        if let Some(session) = self.sessions.get(&id) {
+            log::warn!("{} 发送队列已满, 断开连接", session.name);
+            session.kick.do_send(Kick {
+                reason: "发送队列已满".to_owned(),
+                replaced: false,
+            });
+        }
+        self.remove_session(id);
+    }
+
+    /// 重发到期未确认的消息
+    fn resend_unacked(&mut self) {
+        let now = Instant::now();
+        let mut overflowed = Vec::new();
+        for (id, session) in self.sessions.iter_mut() {
+            if let Some(outbox) = session.outbox.as_mut() {
+                for text in outbox.due(now, &self.delivery) {
+                    if !session.send(text, &self.delivery) {
+                        overflowed.push(*id);
+                        break;
+                    }
                 }
             }
         }
+        for id in overflowed {
+            self.overflow(id);
+        }
+    }
+
+    /// 根据服务端名查找会话id
+    pub(super) fn session_id(&self, server: &str) -> Option<usize> {
+        self.names.get(server).copied()
+    }
+
+    /// 所有已连接会话的id和服务端名
+    pub(super) fn session_names(&self) -> Vec<(usize, String)> {
+        self.sessions
+            .iter()
+            .map(|(id, session)| (*id, session.name.clone()))
+            .collect()
+    }
+
+    /// 分配一个未被使用的会话id
+    fn allocate_id(&mut self) -> usize {
+        loop {
+            let id = self.next_id;
+            self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+            if !self.sessions.contains_key(&id) {
+                return id;
+            }
+        }
     }
-}
 
+    /// 移除会话并退出所有频道
+    fn remove_session(&mut self, id: usize) {
+        if let Some(session) = self.sessions.remove(&id) {
+            if self.names.get(&session.name) == Some(&id) {
+                self.names.remove(&session.name);
+                drop_server_commands(&mut self.pending_commands, &session.name);
+            }
+            let channels: Vec<String> = self.channels.keys().cloned().collect();
+            for channel in channels {
+                self.leave_channel(id, &channel);
+            }
+        }
+    }
+
+    /// 加入频道, 频道不存在时创建
+    fn join_channel(&mut self, id: usize, channel: &str) -> bool {
+        if !is_valid_channel(channel) || !self.sessions.contains_key(&id) {
+            return false;
+        }
+        self.channels
+            .entry(channel.to_owned())
+            .or_default()
+            .insert(id);
+        true
+    }
 
+    /// 离开频道, 频道无成员时移除(默认频道除外)
+    fn leave_channel(&mut self, id: usize, channel: &str) {
+        if let Some(members) = self.channels.get_mut(channel) {
+            members.remove(&id);
+            if members.is_empty() && channel != DEFAULT_CHANNEL {
+                self.channels.remove(channel);
+            }
+        }
+    }
+}
 
+/// 新会话连接, 返回会话id
+/// name: 服务端名, 同名服务端已连接时返回None
+/// takeover: 同名服务端已连接时断开旧连接并接管
+/// protocol: 协议版本, 旧协议的会话只收到聊天消息原文
+/// ack: 是否开启消息确认, 开启后下发的消息需要客户端确认, 未确认的消息会重发
+/// channels: 连接时额外加入的频道
+/// remote_addr: 客户端地址
+/// stats: 会话消息计数
+/// queue: 会话的发送队列, 有新消息时向addr发送`Flush`
 #[derive(Message)]
-#[rtype(usize)]
+#[rtype(result = "Option<usize>")]
 pub struct Connect {
-    pub addr: Recipient<Message>,
+    pub addr: Recipient<Flush>,
+    pub kick: Recipient<Kick>,
+    pub name: String,
+    pub takeover: bool,
+    pub protocol: u32,
+    pub ack: bool,
+    pub channels: Vec<String>,
+    pub remote_addr: Option<String>,
+    pub stats: Arc<SessionStats>,
+    pub queue: Arc<SendQueue>,
 }
 
 impl Handler<Connect> for ChatServer {
-    type Result = usize;
+    type Result = Option<usize>;
 
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        let id = self.rng.gen::<usize>();
-        self.sessions.insert(id, msg.addr);
-        id
+        if let Some(old_id) = self.names.get(&msg.name).copied() {
+            if !msg.takeover {
+                return None;
+            }
+            if let Some(old) = self.sessions.get(&old_id) {
+                old.kick.do_send(Kick {
+                    reason: "服务端已在其他连接登录".to_owned(),
+                    replaced: true,
+                });
+            }
+            self.remove_session(old_id);
+            log::warn!("{} 的旧连接已被接管", msg.name);
+        }
+        let id = self.allocate_id();
+        self.names.insert(msg.name.clone(), id);
+        self.sessions.insert(
+            id,
+            Session {
+                addr: msg.addr,
+                kick: msg.kick,
+                name: msg.name,
+                connected_at: chrono::Local::now().timestamp(),
+                remote_addr: msg.remote_addr,
+                protocol: msg.protocol,
+                stats: msg.stats,
+                queue: msg.queue,
+                outbox: msg.ack.then(Outbox::default),
+            },
+        );
+        self.join_channel(id, DEFAULT_CHANNEL);
+        for channel in &msg.channels {
+            if !self.join_channel(id, channel) {
+                log::warn!("会话 {} 加入频道失败, 频道名非法: {}", id, channel);
+            }
+        }
+        Some(id)
     }
 }
 
+/// 会话断开, 从所有频道移除
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disconnect {
+    pub id: usize,
+}
+
+impl Handler<Disconnect> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+        self.remove_session(msg.id);
+    }
+}
+
+/// 加入频道, 频道名非法时返回false
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct Join {
+    pub id: usize,
+    pub channel: String,
+}
+
+impl Handler<Join> for ChatServer {
+    type Result = bool;
+
+    fn handle(&mut self, msg: Join, _: &mut Context<Self>) -> bool {
+        self.join_channel(msg.id, &msg.channel)
+    }
+}
+
+/// 离开频道
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Leave {
+    pub id: usize,
+    pub channel: String,
+}
+
+impl Handler<Leave> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Leave, _: &mut Context<Self>) {
+        self.leave_channel(msg.id, &msg.channel);
+    }
+}
+
+/// 会话发送的频道消息, 仅频道成员可以发送
+/// player: 发送消息的玩家, 为None时以服务端名计, 不做刷屏和慢速检测
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ClientMessage {
     pub id: usize,
+    pub channel: String,
+    pub player: Option<String>,
     pub msg: String,
 }
 impl Handler<ClientMessage> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
-        self.send_message(msg.msg.as_str(), msg.id);
+        let is_member = self
+            .channels
+            .get(&msg.channel)
+            .is_some_and(|members| members.contains(&msg.id));
+        if !is_member {
+            log::warn!("会话 {} 不在频道 {} 中, 消息已丢弃", msg.id, msg.channel);
+            return;
+        }
+        let Some(session) = self.sessions.get(&msg.id) else {
+            return;
+        };
+        let result = match msg.player.as_deref() {
+            Some(player) => self.moderator.check(&msg.channel, player, &msg.msg),
+            None => self
+                .moderator
+                .check_content(&msg.channel, &session.name, &msg.msg),
+        };
+        if let Err(reason) = result {
+            let text = ServerFrame::Blocked {
+                channel: &msg.channel,
+                reason: &reason,
+            }
+            .to_text();
+            self.deliver(msg.id, &text);
+            return;
+        }
+        self.send_message(&msg.channel, msg.msg.as_str(), Some(msg.id));
+    }
+}
+
+/// 通过HTTP接口发送的玩家聊天消息, 经过聊天审核后广播到频道
+/// player: 发送消息的玩家
+/// text: 用于审核的消息文本
+/// msg: 实际广播的消息内容
+/// 被拦截时返回拦截原因
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct PlayerChat {
+    pub channel: String,
+    pub player: String,
+    pub text: String,
+    pub msg: String,
+}
+
+impl Handler<PlayerChat> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: PlayerChat, _: &mut Context<Self>) -> Self::Result {
+        self.moderator.check(&msg.channel, &msg.player, &msg.text)?;
+        self.send_message(&msg.channel, msg.msg.as_str(), None);
+        Ok(())
     }
 }
 
+/// 向频道内所有成员广播消息
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct BroadcastMessage {
+    pub channel: String,
     pub msg: String,
 }
 
@@ -80,6 +557,146 @@ impl Handler<BroadcastMessage> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: BroadcastMessage, _: &mut Context<Self>) {
-        self.send_message(msg.msg.as_str(), 0);
+        self.send_message(&msg.channel, msg.msg.as_str(), None);
+    }
+}
+
+/// 补发频道内序号大于`since`的聊天记录, 仅频道成员可以请求
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Replay {
+    pub id: usize,
+    pub channel: String,
+    pub since: u64,
+}
+
+impl Handler<Replay> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Replay, _: &mut Context<Self>) {
+        let is_member = self
+            .channels
+            .get(&msg.channel)
+            .is_some_and(|members| members.contains(&msg.id));
+        if !is_member {
+            return;
+        }
+        // 旧协议的会话只补发消息原文
+        let framed = self.is_framed(msg.id);
+        let mut frames = Vec::new();
+        let mut latest = msg.since;
+        let mut truncated = false;
+        if let Some(history) = self.history.get(&msg.channel) {
+            if let Some(oldest) = history.front() {
+                truncated = msg.since.saturating_add(1) < oldest.seq;
+            }
+            for entry in history.iter().filter(|entry| entry.seq > msg.since) {
+                let text = if framed {
+                    ServerFrame::Chat {
+                        channel: &entry.channel,
+                        seq: entry.seq,
+                        msg: &entry.msg,
+                    }
+                    .to_text()
+                } else {
+                    entry.msg.clone()
+                };
+                frames.push(text);
+                latest = entry.seq;
+            }
+        }
+        frames.push(
+            ServerFrame::ReplayEnd {
+                channel: &msg.channel,
+                seq: latest,
+                truncated,
+            }
+            .to_text(),
+        );
+        for text in frames {
+            self.deliver(msg.id, &text);
+        }
+    }
+}
+
+/// 向指定服务端的会话发送消息, 服务端未连接时返回false
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct DirectMessage {
+    pub server: String,
+    pub msg: String,
+}
+
+impl Handler<DirectMessage> for ChatServer {
+    type Result = bool;
+
+    fn handle(&mut self, msg: DirectMessage, _: &mut Context<Self>) -> bool {
+        match self.names.get(&msg.server).copied() {
+            Some(id) => self.deliver(id, &msg.msg),
+            None => false,
+        }
+    }
+}
+
+/// 客户端确认收到消息
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Ack {
+    pub id: usize,
+    pub msg_id: u64,
+}
+
+impl Handler<Ack> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Ack, _: &mut Context<Self>) {
+        let outbox = self
+            .sessions
+            .get_mut(&msg.id)
+            .and_then(|session| session.outbox.as_mut());
+        if let Some(outbox) = outbox {
+            if !outbox.ack(msg.msg_id) {
+                log::debug!("会话 {} 确认了未知消息 {}", msg.id, msg.msg_id);
+            }
+        }
+    }
+}
+
+/// 获取所有已连接会话的信息
+#[derive(Message)]
+#[rtype(result = "Vec<SessionInfo>")]
+pub struct SessionsGet;
+
+impl Handler<SessionsGet> for ChatServer {
+    type Result = Vec<SessionInfo>;
+
+    fn handle(&mut self, _: SessionsGet, _: &mut Context<Self>) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self
+            .sessions
+            .iter()
+            .map(|(id, session)| {
+                let mut channels: Vec<String> = self
+                    .channels
+                    .iter()
+                    .filter(|(_, members)| members.contains(id))
+                    .map(|(channel, _)| channel.clone())
+                    .collect();
+                channels.sort();
+                SessionInfo {
+                    id: *id,
+                    server_name: session.name.clone(),
+                    connected_at: session.connected_at,
+                    remote_addr: session.remote_addr.clone(),
+                    protocol: session.protocol,
+                    channels,
+                    received: session.stats.received.load(Ordering::Relaxed),
+                    sent: session.stats.sent.load(Ordering::Relaxed),
+                    queued: session.queue.len(),
+                    unacked: session.outbox.as_ref().map(Outbox::pending_count),
+                }
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
     }
 }
@@ -0,0 +1,238 @@
+use std::{collections::HashMap, time::Duration};
+
+use actix::prelude::*;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::config::ServerConfig;
+
+use super::{chatserver::ChatServer, player_api::ResponseMessage, protocol::ServerFrame};
+
+/// 默认等待命令结果的时间(秒)
+const DEFAULT_COMMAND_TIMEOUT: u64 = 10;
+
+pub fn command_config(cfg: &mut web::ServiceConfig) {
+    cfg.route("/command/{token_path}", web::post().to(post_command));
+}
+
+/// 游戏服返回的命令执行结果
+#[derive(Debug)]
+pub struct CommandOutput {
+    pub success: bool,
+    pub output: String,
+}
+
+/// 等待结果的远程命令
+#[derive(Debug)]
+pub struct PendingCommand {
+    /// 执行命令的服务端名
+    server: String,
+    tx: oneshot::Sender<CommandOutput>,
+}
+
+/// 已下发的远程命令, 通过rx等待执行结果
+pub struct CommandTicket {
+    pub server: String,
+    pub id: u64,
+    pub rx: oneshot::Receiver<CommandOutput>,
+}
+
+/// 向指定服务端下发命令, server为None时下发给所有已连接的服务端
+/// 返回每个目标服务端的命令凭据
+#[derive(Message)]
+#[rtype(result = "Vec<CommandTicket>")]
+pub struct RemoteCommand {
+    pub server: Option<String>,
+    pub command: String,
+}
+
+impl Handler<RemoteCommand> for ChatServer {
+    type Result = Vec<CommandTicket>;
+
+    fn handle(&mut self, msg: RemoteCommand, _: &mut Context<Self>) -> Vec<CommandTicket> {
+        let targets: Vec<(usize, String)> = match &msg.server {
+            Some(server) => self
+                .session_id(server)
+                .map(|id| vec![(id, server.clone())])
+                .unwrap_or_default(),
+            None => self.session_names(),
+        };
+        let mut tickets = Vec::new();
+        for (session_id, server) in targets {
+            self.next_command_id += 1;
+            let id = self.next_command_id;
+            let text = ServerFrame::Command {
+                id,
+                command: &msg.command,
+            }
+            .to_text();
+            if !self.deliver(session_id, &text) {
+                continue;
+            }
+            let (tx, rx) = oneshot::channel();
+            self.pending_commands.insert(
+                id,
+                PendingCommand {
+                    server: server.clone(),
+                    tx,
+                },
+            );
+            log::info!("向 {} 下发命令 {}: {}", server, id, msg.command);
+            tickets.push(CommandTicket { server, id, rx });
+        }
+        tickets
+    }
+}
+
+/// 游戏服返回命令执行结果, 只接受命令目标服务端的结果
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CommandResponse {
+    pub server: String,
+    pub id: u64,
+    pub success: bool,
+    pub output: String,
+}
+
+impl Handler<CommandResponse> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: CommandResponse, _: &mut Context<Self>) {
+        match self.pending_commands.remove(&msg.id) {
+            Some(pending) if pending.server == msg.server => {
+                let _ = pending.tx.send(CommandOutput {
+                    success: msg.success,
+                    output: msg.output,
+                });
+            }
+            Some(pending) => {
+                log::warn!("{} 返回了不属于它的命令结果 {}", msg.server, msg.id);
+                self.pending_commands.insert(msg.id, pending);
+            }
+            None => log::debug!("命令 {} 已超时或不存在", msg.id),
+        }
+    }
+}
+
+/// 取消等待超时的命令
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CommandCancel {
+    pub ids: Vec<u64>,
+}
+
+impl Handler<CommandCancel> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: CommandCancel, _: &mut Context<Self>) {
+        for id in msg.ids {
+            self.pending_commands.remove(&id);
+        }
+    }
+}
+
+/// 移除服务端所有等待中的命令, 服务端断开时调用
+pub fn drop_server_commands(pending: &mut HashMap<u64, PendingCommand>, server: &str) {
+    pending.retain(|_, command| command.server != server);
+}
+
+/// 远程命令请求
+/// server: 目标服务端名, 不填时下发给所有服务端
+/// command: 控制台命令
+/// timeout: 等待结果的时间(秒), 1~max_command_timeout
+#[derive(Deserialize)]
+pub struct CommandRequest {
+    #[serde(default)]
+    pub server: Option<String>,
+    pub command: String,
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+/// 命令执行结果
+/// status: ok/failed/timeout/disconnected
+#[derive(Serialize)]
+pub struct CommandResult {
+    pub server: String,
+    pub status: &'static str,
+    pub output: String,
+}
+
+// 远程执行命令
+pub async fn post_command(
+    srv: web::Data<Addr<ChatServer>>,
+    config: web::Data<ServerConfig>,
+    request: web::Json<CommandRequest>,
+    token_path: web::Path<String>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    if token_path.as_str() != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let request = request.into_inner();
+    if request.command.trim().is_empty() {
+        return HttpResponse::BadRequest().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "命令不能为空".to_string(),
+        });
+    }
+    let timeout = request.timeout.unwrap_or(DEFAULT_COMMAND_TIMEOUT);
+    if timeout == 0 || timeout > config.max_command_timeout {
+        return HttpResponse::BadRequest().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: format!("等待时间应为1~{}秒", config.max_command_timeout),
+        });
+    }
+    let single = request.server.is_some();
+    let tickets = match srv
+        .send(RemoteCommand {
+            server: request.server,
+            command: request.command,
+        })
+        .await
+    {
+        Ok(tickets) => tickets,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if single && tickets.is_empty() {
+        return HttpResponse::NotFound().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "服务端未连接".to_string(),
+        });
+    }
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(timeout);
+    let mut results = Vec::new();
+    let mut expired = Vec::new();
+    for ticket in tickets {
+        let result = match tokio::time::timeout_at(deadline, ticket.rx).await {
+            Ok(Ok(output)) => CommandResult {
+                server: ticket.server,
+                status: if output.success { "ok" } else { "failed" },
+                output: output.output,
+            },
+            Ok(Err(_)) => CommandResult {
+                server: ticket.server,
+                status: "disconnected",
+                output: String::new(),
+            },
+            Err(_) => {
+                expired.push(ticket.id);
+                CommandResult {
+                    server: ticket.server,
+                    status: "timeout",
+                    output: String::new(),
+                }
+            }
+        };
+        results.push(result);
+    }
+    if !expired.is_empty() {
+        srv.do_send(CommandCancel { ids: expired });
+    }
+    HttpResponse::Ok().json(results)
+}
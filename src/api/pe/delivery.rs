@@ -0,0 +1,152 @@
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use serde_json::value::RawValue;
+
+use crate::config::{DeliveryConfig, OverflowPolicy};
+
+use super::protocol::ServerFrame;
+
+/// 等待确认的消息
+#[derive(Debug)]
+struct Pending {
+    id: u64,
+    /// 带消息id的完整文本帧
+    text: String,
+    attempts: u32,
+    next_retry: Instant,
+}
+
+/// 消息入队结果
+pub enum Enqueue {
+    /// 立即发送该文本帧
+    Send(String),
+    /// 队列已满且配置为断开会话
+    Overflow,
+}
+
+/// 会话的发送队列, 保存已发送但未确认的消息
+#[derive(Debug, Default)]
+pub struct Outbox {
+    next_id: u64,
+    pending: VecDeque<Pending>,
+}
+
+impl Outbox {
+    /// 为消息分配id并加入队列, 返回需要发送的文本帧
+    pub fn enqueue(&mut self, text: &str, config: &DeliveryConfig) -> Enqueue {
+        if self.pending.len() >= config.max_queue.max(1) {
+            match config.overflow {
+                OverflowPolicy::Disconnect => return Enqueue::Overflow,
+                OverflowPolicy::DropOldest => {
+                    if let Some(dropped) = self.pending.pop_front() {
+                        log::warn!("发送队列已满, 丢弃未确认的消息 {}", dropped.id);
+                    }
+                }
+            }
+        }
+        self.next_id += 1;
+        let id = self.next_id;
+        let text = match RawValue::from_string(text.to_owned()) {
+            Ok(frame) => ServerFrame::Deliver { id, frame: &frame }.to_text(),
+            Err(_) => return Enqueue::Send(text.to_owned()),
+        };
+        self.pending.push_back(Pending {
+            id,
+            text: text.clone(),
+            attempts: 1,
+            next_retry: Instant::now() + Duration::from_millis(config.retry_initial),
+        });
+        Enqueue::Send(text)
+    }
+
+    /// 确认消息, 消息不在队列中时返回false
+    pub fn ack(&mut self, id: u64) -> bool {
+        match self.pending.iter().position(|pending| pending.id == id) {
+            Some(index) => {
+                self.pending.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 取出到期需要重发的消息, 等待时间按次数翻倍, 超过最多发送次数的消息被丢弃
+    pub fn due(&mut self, now: Instant, config: &DeliveryConfig) -> Vec<String> {
+        let mut resend = Vec::new();
+        self.pending.retain_mut(|pending| {
+            if pending.next_retry > now {
+                return true;
+            }
+            if pending.attempts >= config.max_attempts {
+                log::warn!("消息 {} 超过最多发送次数未确认, 已丢弃", pending.id);
+                return false;
+            }
+            let backoff = config
+                .retry_initial
+                .saturating_mul(1 << pending.attempts.min(16))
+                .min(config.retry_max);
+            pending.attempts += 1;
+            pending.next_retry = now + Duration::from_millis(backoff);
+            resend.push(pending.text.clone());
+            true
+        });
+        resend
+    }
+
+    /// 未确认的消息数
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+/// 会话的待发送消息, 聊天服务器写入, 会话收到`Flush`后取出发送
+/// 客户端读取过慢时消息在此等待, 数量不超过`max_queue`
+#[derive(Debug, Default)]
+pub struct SendQueue(Mutex<VecDeque<String>>);
+
+/// 消息加入发送队列的结果
+pub enum Queued {
+    /// 队列原本为空, 需要通知会话取出
+    Wake,
+    /// 队列中已有消息, 会话取出时一并发送
+    Pending,
+    /// 队列已满且配置为断开会话
+    Overflow,
+}
+
+impl SendQueue {
+    /// 加入一条消息, 队列已满时按配置丢弃最早的消息或返回`Overflow`
+    pub fn push(&self, text: String, config: &DeliveryConfig) -> Queued {
+        let mut queue = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if queue.len() >= config.max_queue.max(1) {
+            match config.overflow {
+                OverflowPolicy::Disconnect => return Queued::Overflow,
+                OverflowPolicy::DropOldest => {
+                    queue.pop_front();
+                    log::warn!("发送队列已满, 丢弃最早未发出的消息");
+                }
+            }
+        }
+        let wake = queue.is_empty();
+        queue.push_back(text);
+        if wake {
+            Queued::Wake
+        } else {
+            Queued::Pending
+        }
+    }
+
+    /// 取出所有待发送的消息
+    pub fn take(&self) -> VecDeque<String> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// 未发出的消息数
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+}
@@ -0,0 +1,359 @@
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use actix_files::HttpRange;
+use actix_web::{
+    body::SizedStream,
+    http::{
+        header::{
+            ContentType, EntityTag, IfMatch, IfNoneMatch, ACCEPT_RANGES, CONTENT_LENGTH,
+            CONTENT_RANGE, ETAG, IF_RANGE, RANGE,
+        },
+        StatusCode,
+    },
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures_util::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, Take},
+};
+
+use super::{
+    integrity::{open_with_hash, FileMeta, CONTENT_SHA256_HEADER},
+    player_api::ResponseMessage,
+};
+
+/// 临时文件序号, 保证同一文件的并发上传写入不同的临时文件
+static UPLOAD_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// 上传失败的原因
+#[derive(Debug)]
+pub enum UploadError {
+    /// 超过大小限制
+    TooLarge(u64),
+    /// 请求体接收失败
+    Payload,
+    /// 临时文件写入失败
+    Io,
+}
+
+impl UploadError {
+    pub fn response(&self) -> HttpResponse {
+        let message = match self {
+            UploadError::TooLarge(max_size) => format!("文件超过大小限制({}字节)", max_size),
+            UploadError::Payload => "数据接收失败".to_string(),
+            UploadError::Io => "文件写入失败".to_string(),
+        };
+        let mut response = match self {
+            UploadError::TooLarge(_) => HttpResponse::PayloadTooLarge(),
+            UploadError::Payload => HttpResponse::BadRequest(),
+            UploadError::Io => HttpResponse::InternalServerError(),
+        };
+        response.json(ResponseMessage {
+            r#type: "error".to_string(),
+            message,
+        })
+    }
+}
+
+/// 接收完成的上传
+/// size: 字节数
+/// sha256: 内容的SHA-256, 小写十六进制
+#[derive(Debug)]
+pub struct Received {
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// 上传使用的临时文件路径: {path}.upload-{序号}, 写完后重命名为目标文件
+pub fn upload_tmp_path(path: &Path) -> PathBuf {
+    let seq = UPLOAD_SEQ.fetch_add(1, Ordering::Relaxed);
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".upload-{}", seq));
+    path.with_file_name(name)
+}
+
+/// 将请求体逐块写入临时文件, 内存中只保留当前数据块
+/// 声明的Content-Length或实际接收的字节数超过`max_size`时返回错误, 失败时删除临时文件
+pub async fn receive_to_file(
+    req: &HttpRequest,
+    payload: &mut web::Payload,
+    tmp: &Path,
+    max_size: u64,
+) -> Result<Received, UploadError> {
+    let declared = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > max_size) {
+        return Err(UploadError::TooLarge(max_size));
+    }
+    if let Some(parent) = tmp.parent() {
+        tokio::fs::create_dir_all(parent).await.ok();
+    }
+    let result = write_payload(payload, tmp, max_size).await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(tmp).await;
+    }
+    result
+}
+
+async fn write_payload(
+    payload: &mut web::Payload,
+    tmp: &Path,
+    max_size: u64,
+) -> Result<Received, UploadError> {
+    let mut file = File::create(tmp).await.map_err(|_| UploadError::Io)?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|_| UploadError::Payload)?;
+        size += chunk.len() as u64;
+        if size > max_size {
+            return Err(UploadError::TooLarge(max_size));
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await.map_err(|_| UploadError::Io)?;
+    }
+    // tokio的文件写入在后台完成, 返回前等待写入结束
+    file.flush().await.map_err(|_| UploadError::Io)?;
+    Ok(Received {
+        size,
+        sha256: to_hex(&hasher.finalize()),
+    })
+}
+
+/// 字节转换为小写十六进制字符串
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 以流的方式发送文件, 文件不存在时返回404
+/// ETag为文件内容的SHA-256, 支持If-None-Match(304)、If-Match(412)和带If-Range的Range请求续传
+pub async fn serve_file(req: &HttpRequest, path: &Path) -> HttpResponse {
+    let Ok((mut file, hash)) = open_with_hash(path).await else {
+        return not_found();
+    };
+    let etag = EntityTag::new_strong(hash.sha256.clone());
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header((ETAG, etag.clone()))
+        .insert_header((CONTENT_SHA256_HEADER, hash.sha256.as_str()))
+        .insert_header((ACCEPT_RANGES, "bytes"));
+
+    if let Some(IfMatch::Items(items)) = req.get_header::<IfMatch>() {
+        if !items.iter().any(|item| item.strong_eq(&etag)) {
+            return response.status(StatusCode::PRECONDITION_FAILED).finish();
+        }
+    }
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(items)) => items.iter().any(|item| item.weak_eq(&etag)),
+        None => false,
+    };
+    if not_modified {
+        return response.status(StatusCode::NOT_MODIFIED).finish();
+    }
+
+    // If-Range与当前ETag不一致时说明文件已改变, 忽略Range返回完整文件
+    let range = req.headers().get(RANGE).filter(|_| {
+        req.headers()
+            .get(IF_RANGE)
+            .is_none_or(|value| value.to_str().ok() == Some(etag.to_string().as_str()))
+    });
+    let (mut offset, mut length) = (0, hash.size);
+    if let Some(range) = range {
+        let ranges = range
+            .to_str()
+            .ok()
+            .and_then(|range| HttpRange::parse(range, hash.size).ok());
+        // 空文件的后缀范围解析为长度0, 同样无法满足
+        let range = ranges
+            .as_ref()
+            .and_then(|ranges| ranges.first())
+            .filter(|range| range.length > 0);
+        let Some(range) = range else {
+            return response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .insert_header((CONTENT_RANGE, format!("bytes */{}", hash.size)))
+                .finish();
+        };
+        (offset, length) = (range.start, range.length);
+        response.status(StatusCode::PARTIAL_CONTENT).insert_header((
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{}", offset, offset + length - 1, hash.size),
+        ));
+    }
+    if file.seek(SeekFrom::Start(offset)).await.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    response
+        .content_type(ContentType::octet_stream())
+        .body(SizedStream::new(length, read_stream(file.take(length))))
+}
+
+/// 每次读取64KiB, 发送前不把整个文件读入内存
+fn read_stream(file: Take<File>) -> impl Stream<Item = Result<web::Bytes, std::io::Error>> {
+    futures_util::stream::try_unfold(file, |mut file| async move {
+        let mut buf = vec![0; 64 * 1024];
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.truncate(n);
+        Ok(Some((web::Bytes::from(buf), file)))
+    })
+}
+
+/// 文件的大小、SHA-256和修改时间, 文件不存在时返回404
+pub async fn serve_meta(path: &Path) -> HttpResponse {
+    match open_with_hash(path).await {
+        Ok((_, hash)) => HttpResponse::Ok()
+            .insert_header((ETAG, EntityTag::new_strong(hash.sha256.clone())))
+            .json(FileMeta::from(hash)),
+        Err(_) => not_found(),
+    }
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(ResponseMessage {
+        r#type: "error".to_string(),
+        message: "文件不存在".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        body::to_bytes,
+        http::header::{HeaderName, HeaderValue, IF_MATCH, IF_NONE_MATCH},
+        test::TestRequest,
+    };
+
+    use super::*;
+    use crate::api::pe::integrity::remove_file;
+
+    /// 临时目录中的测试文件, 返回路径和ETag
+    async fn test_file(name: &str, content: &[u8]) -> (std::path::PathBuf, String) {
+        let path =
+            std::env::temp_dir().join(format!("file-stream-{}-{}", std::process::id(), name));
+        tokio::fs::write(&path, content).await.unwrap();
+        let sha256 = to_hex(&Sha256::digest(content));
+        (path, format!("\"{}\"", sha256))
+    }
+
+    async fn get(path: &Path, headers: &[(HeaderName, &str)]) -> (StatusCode, HttpResponse) {
+        let mut req = TestRequest::get();
+        for (name, value) in headers {
+            req = req.insert_header((name.clone(), HeaderValue::from_str(value).unwrap()));
+        }
+        let response = serve_file(&req.to_http_request(), path).await;
+        (response.status(), response)
+    }
+
+    fn header(response: &HttpResponse, name: HeaderName) -> Option<&str> {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    async fn body(response: HttpResponse) -> Vec<u8> {
+        to_bytes(response.into_body()).await.unwrap().to_vec()
+    }
+
+    #[actix_web::test]
+    async fn serves_ranges() {
+        let (path, etag) = test_file("ranges", b"0123456789").await;
+
+        let (status, response) = get(&path, &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&response, ETAG), Some(etag.as_str()));
+        assert_eq!(header(&response, ACCEPT_RANGES), Some("bytes"));
+        assert_eq!(body(response).await, b"0123456789");
+
+        let (status, response) = get(&path, &[(RANGE, "bytes=2-5")]).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header(&response, CONTENT_RANGE), Some("bytes 2-5/10"));
+        assert_eq!(body(response).await, b"2345");
+
+        let (status, response) = get(&path, &[(RANGE, "bytes=-3")]).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header(&response, CONTENT_RANGE), Some("bytes 7-9/10"));
+        assert_eq!(body(response).await, b"789");
+
+        let (status, response) = get(&path, &[(RANGE, "bytes=8-")]).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body(response).await, b"89");
+
+        remove_file(&path).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn rejects_unsatisfiable_ranges() {
+        let (path, _) = test_file("unsatisfiable", b"0123456789").await;
+        for range in ["bytes=20-30", "bytes=abc", "items=0-1"] {
+            let (status, response) = get(&path, &[(RANGE, range)]).await;
+            assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE, "{}", range);
+            assert_eq!(header(&response, CONTENT_RANGE), Some("bytes */10"));
+        }
+        remove_file(&path).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn empty_file() {
+        let (path, _) = test_file("empty", b"").await;
+        let (status, response) = get(&path, &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body(response).await.is_empty());
+
+        for range in ["bytes=-5", "bytes=0-", "bytes=0-0"] {
+            let (status, response) = get(&path, &[(RANGE, range)]).await;
+            assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE, "{}", range);
+            assert_eq!(header(&response, CONTENT_RANGE), Some("bytes */0"));
+        }
+        remove_file(&path).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn if_range() {
+        let (path, etag) = test_file("if-range", b"0123456789").await;
+
+        let (status, response) = get(&path, &[(RANGE, "bytes=0-1"), (IF_RANGE, &etag)]).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body(response).await, b"01");
+
+        // 文件已改变时忽略Range, 返回完整文件
+        let stale = [(RANGE, "bytes=0-1"), (IF_RANGE, "\"stale\"")];
+        let (status, response) = get(&path, &stale).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&response, CONTENT_RANGE), None);
+        assert_eq!(body(response).await, b"0123456789");
+
+        remove_file(&path).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn conditional_requests() {
+        let (path, etag) = test_file("conditional", b"0123456789").await;
+
+        let (status, _) = get(&path, &[(IF_NONE_MATCH, &etag)]).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        let (status, _) = get(&path, &[(IF_NONE_MATCH, "\"stale\"")]).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = get(&path, &[(IF_MATCH, "\"stale\"")]).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        let (status, _) = get(&path, &[(IF_MATCH, &etag)]).await;
+        assert_eq!(status, StatusCode::OK);
+
+        remove_file(&path).await.unwrap();
+        let (status, _) = get(&path, &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
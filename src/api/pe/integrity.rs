@@ -0,0 +1,169 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use actix_web::{HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use super::{
+    file_stream::{to_hex, Received},
+    player_api::ResponseMessage,
+};
+
+/// 上传时客户端声明的文件SHA-256, 下载时返回文件的SHA-256
+pub const CONTENT_SHA256_HEADER: &str = "X-Content-Sha256";
+
+/// 文件的SHA-256, 与计算时的文件大小和修改时间一起保存在{文件}.sha256
+/// 文件被其他途径替换后大小或修改时间不一致, 读取时重新计算
+/// modified: 修改时间(unix纳秒)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileHash {
+    pub sha256: String,
+    pub size: u64,
+    pub modified: u64,
+}
+
+/// 文件元数据
+/// modified: 修改时间(unix秒)
+#[derive(Serialize, Debug)]
+pub struct FileMeta {
+    pub size: u64,
+    pub sha256: String,
+    pub modified: u64,
+}
+
+impl From<FileHash> for FileMeta {
+    fn from(hash: FileHash) -> FileMeta {
+        FileMeta {
+            size: hash.size,
+            sha256: hash.sha256,
+            modified: hash.modified / 1_000_000_000,
+        }
+    }
+}
+
+/// 校验和文件路径: {path}.sha256
+pub fn hash_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".sha256");
+    path.with_file_name(name)
+}
+
+fn stamp(meta: &std::fs::Metadata) -> (u64, u64) {
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or_default();
+    (meta.len(), modified)
+}
+
+/// 将上传完成的临时文件重命名为目标文件并保存校验和
+/// 大小和修改时间取自临时文件, 重命名后保持不变
+pub async fn commit_file(tmp: &Path, path: &Path, sha256: &str) -> io::Result<FileHash> {
+    let (size, modified) = stamp(&tokio::fs::metadata(tmp).await?);
+    tokio::fs::rename(tmp, path).await?;
+    let hash = FileHash {
+        sha256: sha256.to_owned(),
+        size,
+        modified,
+    };
+    // 校验和写入失败不影响文件本身, 读取时会重新计算
+    let _ = tokio::fs::write(hash_path(path), serde_json::to_vec(&hash)?).await;
+    Ok(hash)
+}
+
+/// 同`commit_file`, 供actor在处理消息时同步提交, 检查与替换之间不会处理其他消息
+pub fn commit_file_sync(tmp: &Path, path: &Path, sha256: &str) -> io::Result<FileHash> {
+    let (size, modified) = stamp(&std::fs::metadata(tmp)?);
+    std::fs::rename(tmp, path)?;
+    let hash = FileHash {
+        sha256: sha256.to_owned(),
+        size,
+        modified,
+    };
+    let _ = std::fs::write(hash_path(path), serde_json::to_vec(&hash)?);
+    Ok(hash)
+}
+
+/// 打开文件并取得其校验和, 没有记录或记录已过期时按打开的文件重新计算
+/// 返回的文件位于开头, 与校验和对应同一份内容
+pub async fn open_with_hash(path: &Path) -> io::Result<(File, FileHash)> {
+    let mut file = File::open(path).await?;
+    let (size, modified) = stamp(&file.metadata().await?);
+    let saved = tokio::fs::read(hash_path(path))
+        .await
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<FileHash>(&bytes).ok());
+    if let Some(hash) = saved {
+        if hash.size == size && hash.modified == modified {
+            return Ok((file, hash));
+        }
+    }
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    file.rewind().await?;
+    let hash = FileHash {
+        sha256: to_hex(&hasher.finalize()),
+        size,
+        modified,
+    };
+    let _ = tokio::fs::write(hash_path(path), serde_json::to_vec(&hash)?).await;
+    Ok((file, hash))
+}
+
+/// 删除文件及其校验和
+pub async fn remove_file(path: &Path) -> io::Result<()> {
+    let _ = tokio::fs::remove_file(hash_path(path)).await;
+    tokio::fs::remove_file(path).await
+}
+
+/// 读取请求头中声明的SHA-256, 格式错误时返回400
+pub fn expected_sha256(req: &HttpRequest) -> Result<Option<String>, HttpResponse> {
+    let Some(value) = req.headers().get(CONTENT_SHA256_HEADER) else {
+        return Ok(None);
+    };
+    match value.to_str().ok().and_then(parse_sha256) {
+        Some(sha256) => Ok(Some(sha256)),
+        None => Err(HttpResponse::BadRequest().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: format!("{}应为64位十六进制", CONTENT_SHA256_HEADER),
+        })),
+    }
+}
+
+/// 十六进制SHA-256, 统一为小写
+pub fn parse_sha256(value: &str) -> Option<String> {
+    let value = value.trim();
+    (value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| value.to_ascii_lowercase())
+}
+
+/// 检查接收到的内容与声明的SHA-256是否一致, 不一致时返回400
+pub fn verify(expected: Option<&str>, received: &Received) -> Result<(), HttpResponse> {
+    match expected {
+        Some(expected) if expected != received.sha256 => {
+            Err(HttpResponse::BadRequest().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message: format!("文件校验和不匹配, 实际为{}", received.sha256),
+            }))
+        }
+        _ => Ok(()),
+    }
+}
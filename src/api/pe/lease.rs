@@ -0,0 +1,472 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use actix::prelude::*;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::ServerConfig, sql::nbt_revision::NbtMeta};
+
+use super::{
+    integrity::{commit_file_sync, FileHash},
+    nbt::save_meta_sync,
+    player_api::ResponseMessage,
+    storage::StorageKey,
+    transfer::TransferManager,
+};
+
+pub fn lease_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/lease")
+            .route(
+                "/{player}/acquire/{token_path}",
+                web::post().to(lease_acquire),
+            )
+            .route("/{player}/renew/{token_path}", web::post().to(lease_renew))
+            .route(
+                "/{player}/release/{token_path}",
+                web::post().to(lease_release),
+            )
+            .route("/{player}/{token_path}", web::get().to(lease_get)),
+    );
+}
+
+/// 玩家数据租约, 持有租约的服务端才能写入玩家nbt
+/// server: 持有租约的服务端
+/// acquired_at: 获取时间(unix秒)
+/// expires_at: 到期时间(unix秒), 到期前需续约
+#[derive(Serialize, Debug, Clone)]
+pub struct Lease {
+    pub player: String,
+    pub server: String,
+    pub acquired_at: i64,
+    pub expires_at: i64,
+}
+
+/// 所有玩家的租约, 到期的租约视为不存在
+#[derive(Debug)]
+pub struct Leases {
+    leases: HashMap<String, Lease>,
+    /// 默认租期(秒)
+    ttl: u64,
+    /// 写入玩家nbt是否必须持有租约, 关闭时无人持有租约的玩家可以直接写入
+    required: bool,
+}
+
+impl Leases {
+    pub fn new(ttl: u64, required: bool) -> Leases {
+        Leases {
+            leases: HashMap::new(),
+            ttl,
+            required,
+        }
+    }
+
+    /// 玩家当前有效的租约
+    pub fn holder(&self, player: &str) -> Option<&Lease> {
+        let now = chrono::Local::now().timestamp();
+        self.leases
+            .get(player)
+            .filter(|lease| lease.expires_at > now)
+    }
+
+    /// 获取租约, 已持有时续约, 被其他服务端持有时返回错误
+    pub fn acquire(
+        &mut self,
+        player: &str,
+        server: &str,
+        ttl: Option<u64>,
+    ) -> Result<Lease, String> {
+        let now = chrono::Local::now().timestamp();
+        let acquired_at = match self.holder(player) {
+            Some(lease) if lease.server != server => {
+                return Err(format!("租约由 {} 持有", lease.server));
+            }
+            Some(lease) => lease.acquired_at,
+            None => now,
+        };
+        let ttl = i64::try_from(ttl.unwrap_or(self.ttl)).unwrap_or(i64::MAX);
+        let lease = Lease {
+            player: player.to_owned(),
+            server: server.to_owned(),
+            acquired_at,
+            expires_at: now.saturating_add(ttl),
+        };
+        self.leases.insert(player.to_owned(), lease.clone());
+        Ok(lease)
+    }
+
+    /// 续约, 只有持有者可以续约
+    pub fn renew(&mut self, player: &str, server: &str, ttl: Option<u64>) -> Result<Lease, String> {
+        match self.holder(player) {
+            Some(lease) if lease.server == server => self.acquire(player, server, ttl),
+            _ => Err("未持有租约".to_string()),
+        }
+    }
+
+    /// 释放租约, 只有持有者可以释放
+    pub fn release(&mut self, player: &str, server: &str) -> Result<Lease, String> {
+        match self.holder(player) {
+            Some(lease) if lease.server == server => self
+                .leases
+                .remove(player)
+                .ok_or_else(|| "未持有租约".to_string()),
+            _ => Err("未持有租约".to_string()),
+        }
+    }
+
+    /// 检查服务端能否写入玩家nbt
+    pub fn check_write(&self, player: &str, server: Option<&str>) -> Result<(), String> {
+        match self.holder(player) {
+            Some(lease) if Some(lease.server.as_str()) == server => Ok(()),
+            Some(lease) => Err(format!("租约由 {} 持有", lease.server)),
+            None if self.required => Err("未持有租约".to_string()),
+            None => Ok(()),
+        }
+    }
+
+    /// 将租约交给玩家转移的目标服务端
+    pub fn hand_over(&mut self, player: &str, server: &str) {
+        self.leases.remove(player);
+        let _ = self.acquire(player, server, None);
+    }
+
+    /// 清理到期的租约
+    pub fn clear_expired(&mut self) {
+        let now = chrono::Local::now().timestamp();
+        self.leases.retain(|_, lease| lease.expires_at > now);
+    }
+}
+
+/// 获取或续约租约, 玩家正在转移时拒绝
+#[derive(Message)]
+#[rtype(result = "Result<Lease, String>")]
+pub struct LeaseAcquire {
+    pub player: String,
+    pub server: String,
+    pub ttl: Option<u64>,
+}
+
+impl Handler<LeaseAcquire> for TransferManager {
+    type Result = Result<Lease, String>;
+
+    fn handle(&mut self, msg: LeaseAcquire, _: &mut Context<Self>) -> Self::Result {
+        if self.is_transferring(&msg.player) {
+            return Err("玩家正在转移中".to_string());
+        }
+        self.leases.acquire(&msg.player, &msg.server, msg.ttl)
+    }
+}
+
+/// 续约
+#[derive(Message)]
+#[rtype(result = "Result<Lease, String>")]
+pub struct LeaseRenew {
+    pub player: String,
+    pub server: String,
+    pub ttl: Option<u64>,
+}
+
+impl Handler<LeaseRenew> for TransferManager {
+    type Result = Result<Lease, String>;
+
+    fn handle(&mut self, msg: LeaseRenew, _: &mut Context<Self>) -> Self::Result {
+        self.leases.renew(&msg.player, &msg.server, msg.ttl)
+    }
+}
+
+/// 释放租约
+#[derive(Message)]
+#[rtype(result = "Result<Lease, String>")]
+pub struct LeaseRelease {
+    pub player: String,
+    pub server: String,
+}
+
+impl Handler<LeaseRelease> for TransferManager {
+    type Result = Result<Lease, String>;
+
+    fn handle(&mut self, msg: LeaseRelease, _: &mut Context<Self>) -> Self::Result {
+        self.leases.release(&msg.player, &msg.server)
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<Lease>")]
+pub struct LeaseGet {
+    pub player: String,
+}
+
+impl Handler<LeaseGet> for TransferManager {
+    type Result = Option<Lease>;
+
+    fn handle(&mut self, msg: LeaseGet, _: &mut Context<Self>) -> Option<Lease> {
+        self.leases.holder(&msg.player).cloned()
+    }
+}
+
+/// 检查服务端能否写入玩家nbt: 玩家正在转移或租约由其他服务端持有时拒绝
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct NbtWriteCheck {
+    pub player: String,
+    pub server: Option<String>,
+}
+
+impl Handler<NbtWriteCheck> for TransferManager {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: NbtWriteCheck, _: &mut Context<Self>) -> Self::Result {
+        self.check_nbt_write(&msg.player, msg.server.as_deref())
+    }
+}
+
+impl TransferManager {
+    /// 检查服务端能否写入玩家nbt
+    fn check_nbt_write(&self, player: &str, server: Option<&str>) -> Result<(), String> {
+        if self.is_transferring(player) {
+            return Err("玩家正在转移中".to_string());
+        }
+        self.leases.check_write(player, server)
+    }
+}
+
+/// 玩家nbt提交失败的原因
+#[derive(Debug)]
+pub enum NbtCommitError {
+    /// 玩家正在转移或租约由其他服务端持有
+    Rejected(String),
+    /// 替换文件失败
+    Io,
+}
+
+/// 提交已写入临时文件的玩家nbt, 与`NbtWriteCheck`相同的检查通过后替换玩家nbt并保存校验和与元数据
+/// 检查与替换在同一条消息中完成, 上传期间开始的转移或租约变更会使提交被拒绝
+/// 提交失败时删除临时文件, 玩家nbt保持不变
+#[derive(Message)]
+#[rtype(result = "Result<FileHash, NbtCommitError>")]
+pub struct NbtCommit {
+    pub player: String,
+    pub server: Option<String>,
+    pub tmp: PathBuf,
+    pub path: PathBuf,
+    pub sha256: String,
+    pub meta: NbtMeta,
+}
+
+impl Handler<NbtCommit> for TransferManager {
+    type Result = Result<FileHash, NbtCommitError>;
+
+    fn handle(&mut self, msg: NbtCommit, _: &mut Context<Self>) -> Self::Result {
+        if let Err(message) = self.check_nbt_write(&msg.player, msg.server.as_deref()) {
+            let _ = std::fs::remove_file(&msg.tmp);
+            return Err(NbtCommitError::Rejected(message));
+        }
+        let hash = commit_file_sync(&msg.tmp, &msg.path, &msg.sha256).map_err(|err| {
+            log::error!("玩家 {} 的nbt提交失败: {}", msg.player, err);
+            let _ = std::fs::remove_file(&msg.tmp);
+            NbtCommitError::Io
+        })?;
+        save_meta_sync(&msg.path, &hash.sha256, &msg.meta);
+        Ok(hash)
+    }
+}
+
+/// 管理员回滚时提交已写入临时文件的玩家nbt, 只检查玩家是否正在转移, 不检查租约
+/// 检查与替换在同一条消息中完成, 失败时删除临时文件
+#[derive(Message)]
+#[rtype(result = "Result<FileHash, NbtCommitError>")]
+pub struct NbtRestore {
+    pub player: String,
+    pub tmp: PathBuf,
+    pub path: PathBuf,
+    pub sha256: String,
+    pub meta: NbtMeta,
+}
+
+impl Handler<NbtRestore> for TransferManager {
+    type Result = Result<FileHash, NbtCommitError>;
+
+    fn handle(&mut self, msg: NbtRestore, _: &mut Context<Self>) -> Self::Result {
+        if self.is_transferring(&msg.player) {
+            let _ = std::fs::remove_file(&msg.tmp);
+            return Err(NbtCommitError::Rejected("玩家正在转移中".to_string()));
+        }
+        let hash = commit_file_sync(&msg.tmp, &msg.path, &msg.sha256).map_err(|err| {
+            log::error!("玩家 {} 的nbt回滚失败: {}", msg.player, err);
+            let _ = std::fs::remove_file(&msg.tmp);
+            NbtCommitError::Io
+        })?;
+        save_meta_sync(&msg.path, &hash.sha256, &msg.meta);
+        Ok(hash)
+    }
+}
+
+/// 租约操作参数
+/// server: 发起操作的服务端
+/// ttl: 租期(秒), 不填使用默认租期, 不能超过nbt_lease_max_ttl
+#[derive(Deserialize)]
+pub struct LeaseQuery {
+    pub server: String,
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+/// 检查请求的租期, 为0或超过最长租期时返回400
+fn check_ttl(ttl: Option<u64>, config: &ServerConfig) -> Result<(), HttpResponse> {
+    match ttl {
+        Some(ttl) if ttl == 0 || ttl > config.nbt_lease_max_ttl => Err(HttpResponse::BadRequest()
+            .json(ResponseMessage {
+                r#type: "error".to_string(),
+                message: format!("租期应为1~{}秒", config.nbt_lease_max_ttl),
+            })),
+        _ => Ok(()),
+    }
+}
+
+fn lease_response(result: Result<Result<Lease, String>, MailboxError>) -> HttpResponse {
+    match result {
+        Ok(Ok(lease)) => HttpResponse::Ok().json(lease),
+        Ok(Err(message)) => HttpResponse::Conflict().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message,
+        }),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// 获取玩家数据租约
+pub async fn lease_acquire(
+    transfers: web::Data<Addr<TransferManager>>,
+    path: web::Path<(String, String)>,
+    query: web::Query<LeaseQuery>,
+    config: web::Data<ServerConfig>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (player, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let player = match StorageKey::player(&player) {
+        Ok(player) => player,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message: message.to_string(),
+            })
+        }
+    };
+    let query = query.into_inner();
+    if let Err(response) = check_ttl(query.ttl, &config) {
+        return response;
+    }
+    let msg = LeaseAcquire {
+        player: player.as_str().to_owned(),
+        server: query.server,
+        ttl: query.ttl,
+    };
+    lease_response(transfers.send(msg).await)
+}
+
+// 续约
+pub async fn lease_renew(
+    transfers: web::Data<Addr<TransferManager>>,
+    path: web::Path<(String, String)>,
+    query: web::Query<LeaseQuery>,
+    config: web::Data<ServerConfig>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (player, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let player = match StorageKey::player(&player) {
+        Ok(player) => player,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message: message.to_string(),
+            })
+        }
+    };
+    let query = query.into_inner();
+    if let Err(response) = check_ttl(query.ttl, &config) {
+        return response;
+    }
+    let msg = LeaseRenew {
+        player: player.as_str().to_owned(),
+        server: query.server,
+        ttl: query.ttl,
+    };
+    lease_response(transfers.send(msg).await)
+}
+
+// 释放租约
+pub async fn lease_release(
+    transfers: web::Data<Addr<TransferManager>>,
+    path: web::Path<(String, String)>,
+    query: web::Query<LeaseQuery>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (player, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let player = match StorageKey::player(&player) {
+        Ok(player) => player,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message: message.to_string(),
+            })
+        }
+    };
+    let msg = LeaseRelease {
+        player: player.as_str().to_owned(),
+        server: query.into_inner().server,
+    };
+    lease_response(transfers.send(msg).await)
+}
+
+// 查询玩家当前的租约
+pub async fn lease_get(
+    transfers: web::Data<Addr<TransferManager>>,
+    path: web::Path<(String, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (player, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let player = match StorageKey::player(&player) {
+        Ok(player) => player,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message: message.to_string(),
+            })
+        }
+    };
+    let msg = LeaseGet {
+        player: player.as_str().to_owned(),
+    };
+    match transfers.send(msg).await {
+        Ok(Some(lease)) => HttpResponse::Ok().json(lease),
+        Ok(None) => HttpResponse::NotFound().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "无人持有租约".to_string(),
+        }),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -1,5 +1,20 @@
+pub mod binary;
 pub mod chatserver;
+pub mod command;
+pub mod delivery;
+pub mod file_stream;
+pub mod integrity;
+pub mod lease;
 pub mod session;
+pub mod storage;
+pub mod moderation;
+pub mod nbt;
+pub mod nbt_api;
 pub mod player;
 pub mod player_api;
-pub mod world;
\ No newline at end of file
+pub mod protocol;
+pub mod revision;
+pub mod transfer;
+pub mod whisper;
+pub mod world;
+pub mod world_upload;
\ No newline at end of file
@@ -0,0 +1,481 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use actix_web::{web, HttpResponse};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::ModerationConfig;
+
+use super::{
+    chatserver::{self, ChatServer},
+    player_api::ResponseMessage,
+};
+
+/// 私信审核时使用的频道名, 不是合法的频道名, 不会与真实频道冲突
+pub const WHISPER_CHANNEL: &str = "@whisper";
+/// 清理过期的慢速模式和刷屏检测记录的间隔
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn moderation_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/moderation")
+            .route("/mute/{token_path}", web::post().to(mute_player))
+            .route(
+                "/mute/{player}/{token_path}",
+                web::delete().to(unmute_player),
+            )
+            .route("/mutes/{token_path}", web::get().to(mutes_get))
+            .route("/blocked/{token_path}", web::get().to(blocked_get))
+            .route(
+                "/slow_mode/{channel}/{token_path}",
+                web::put().to(slow_mode_set),
+            ),
+    );
+}
+
+/// 被拦截的消息
+/// time: 拦截时间(unix秒)
+/// channel: 频道
+/// sender: 发送者
+/// msg: 消息内容
+/// reason: 拦截原因
+#[derive(Serialize, Debug, Clone)]
+pub struct BlockedMessage {
+    pub time: i64,
+    pub channel: String,
+    pub sender: String,
+    pub msg: String,
+    pub reason: String,
+}
+
+/// 禁言记录, 以玩家名为键保存在内存中, 重启后丢失
+/// player: 玩家名
+/// until: 解除时间(unix秒), None为永久禁言
+/// reason: 禁言原因
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mute {
+    pub player: String,
+    #[serde(default)]
+    pub until: Option<i64>,
+    #[serde(default)]
+    pub reason: String,
+}
+
+/// 聊天审核: 屏蔽词/正则过滤、禁言、频道慢速模式和刷屏检测
+#[derive(Debug)]
+pub struct Moderator {
+    filters: Vec<Regex>,
+    flood_max_messages: usize,
+    flood_window: Duration,
+    /// 频道名 -> 慢速模式间隔
+    slow_mode: HashMap<String, Duration>,
+    /// 玩家名 -> 禁言记录, 不持久化
+    mutes: HashMap<String, Mute>,
+    /// (频道, 发送者) -> 上一条消息时间
+    last_sent: HashMap<(String, String), Instant>,
+    /// 发送者 -> 刷屏检测窗口内的消息时间
+    recent: HashMap<String, VecDeque<Instant>>,
+    /// 上次清理过期记录的时间
+    last_sweep: Instant,
+    blocked: VecDeque<BlockedMessage>,
+    blocked_log_size: usize,
+}
+
+impl Moderator {
+    pub fn new(config: &ModerationConfig) -> Moderator {
+        let words = config
+            .blocked_words
+            .iter()
+            .map(|word| format!("(?i){}", regex::escape(word)));
+        let filters = words
+            .chain(config.blocked_patterns.iter().cloned())
+            .filter_map(|pattern| match Regex::new(&pattern) {
+                Ok(regex) => Some(regex),
+                Err(err) => {
+                    log::error!("屏蔽规则 {} 无效: {}", pattern, err);
+                    None
+                }
+            })
+            .collect();
+        Moderator {
+            filters,
+            flood_max_messages: config.flood_max_messages,
+            flood_window: Duration::from_secs(config.flood_window),
+            slow_mode: config
+                .slow_mode
+                .iter()
+                .map(|(channel, secs)| (channel.clone(), Duration::from_secs(*secs)))
+                .collect(),
+            mutes: HashMap::new(),
+            last_sent: HashMap::new(),
+            recent: HashMap::new(),
+            last_sweep: Instant::now(),
+            blocked: VecDeque::new(),
+            blocked_log_size: config.blocked_log_size,
+        }
+    }
+
+    /// 审核一条玩家消息, 被拦截时返回原因并记录到拦截日志
+    pub fn check(&mut self, channel: &str, sender: &str, msg: &str) -> Result<(), String> {
+        self.check_with(channel, sender, msg, true)
+    }
+
+    /// 审核没有玩家身份的消息(旧协议的纯文本聊天), 只检查禁言和屏蔽内容
+    /// 这类消息以服务端名计, 做刷屏和慢速检测会拦截整个服务端的聊天
+    pub fn check_content(&mut self, channel: &str, sender: &str, msg: &str) -> Result<(), String> {
+        self.check_with(channel, sender, msg, false)
+    }
+
+    fn check_with(
+        &mut self,
+        channel: &str,
+        sender: &str,
+        msg: &str,
+        rate_limit: bool,
+    ) -> Result<(), String> {
+        let result = self.check_inner(channel, sender, msg, rate_limit);
+        if let Err(reason) = &result {
+            log::warn!("已拦截 {} 在频道 {} 的消息: {}", sender, channel, reason);
+            self.blocked.push_back(BlockedMessage {
+                time: chrono::Local::now().timestamp(),
+                channel: channel.to_owned(),
+                sender: sender.to_owned(),
+                msg: msg.to_owned(),
+                reason: reason.clone(),
+            });
+            while self.blocked.len() > self.blocked_log_size {
+                self.blocked.pop_front();
+            }
+        }
+        result
+    }
+
+    fn check_inner(
+        &mut self,
+        channel: &str,
+        sender: &str,
+        msg: &str,
+        rate_limit: bool,
+    ) -> Result<(), String> {
+        let now = chrono::Local::now().timestamp();
+        if let Some(mute) = self.mutes.get(sender) {
+            if mute.until.is_none_or(|until| until > now) {
+                return Err("已被禁言".to_string());
+            }
+            self.mutes.remove(sender);
+        }
+        if self.filters.iter().any(|filter| filter.is_match(msg)) {
+            return Err("包含屏蔽内容".to_string());
+        }
+        if !rate_limit {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        self.sweep(now);
+        if let Some(interval) = self.slow_mode.get(channel) {
+            let key = (channel.to_owned(), sender.to_owned());
+            if let Some(last) = self.last_sent.get(&key) {
+                if now.duration_since(*last) < *interval {
+                    return Err("频道慢速模式中, 发送过快".to_string());
+                }
+            }
+            self.last_sent.insert(key, now);
+        }
+        if self.flood_max_messages > 0 {
+            let recent = self.recent.entry(sender.to_owned()).or_default();
+            while recent
+                .front()
+                .is_some_and(|time| now.duration_since(*time) > self.flood_window)
+            {
+                recent.pop_front();
+            }
+            if recent.len() >= self.flood_max_messages {
+                return Err("刷屏".to_string());
+            }
+            recent.push_back(now);
+        }
+        Ok(())
+    }
+
+    /// 每隔SWEEP_INTERVAL删除已不影响判断的记录, 不再发言的发送者不会一直占用内存
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.last_sweep) < SWEEP_INTERVAL {
+            return;
+        }
+        self.last_sweep = now;
+        let slow_mode = &self.slow_mode;
+        self.last_sent.retain(|(channel, _), last| {
+            slow_mode
+                .get(channel)
+                .is_some_and(|interval| now.duration_since(*last) < *interval)
+        });
+        let window = self.flood_window;
+        self.recent.retain(|_, recent| {
+            while recent
+                .front()
+                .is_some_and(|time| now.duration_since(*time) > window)
+            {
+                recent.pop_front();
+            }
+            !recent.is_empty()
+        });
+    }
+
+    /// 当前生效的禁言列表
+    pub fn mutes(&mut self) -> Vec<Mute> {
+        let now = chrono::Local::now().timestamp();
+        self.mutes
+            .retain(|_, mute| mute.until.is_none_or(|until| until > now));
+        self.mutes.values().cloned().collect()
+    }
+}
+
+/// 审核私信, 与频道消息共用禁言、屏蔽规则和刷屏检测, 被拦截时返回原因
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct WhisperCheck {
+    pub from: String,
+    pub msg: String,
+}
+
+impl Handler<WhisperCheck> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: WhisperCheck, _: &mut Context<Self>) -> Self::Result {
+        self.moderator.check(WHISPER_CHANNEL, &msg.from, &msg.msg)
+    }
+}
+
+/// 禁言玩家, 覆盖已有的禁言
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MuteAdd(pub Mute);
+
+impl Handler<MuteAdd> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: MuteAdd, _: &mut Context<Self>) {
+        self.moderator.mutes.insert(msg.0.player.clone(), msg.0);
+    }
+}
+
+/// 解除禁言, 玩家未被禁言时返回false
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct MuteRemove {
+    pub player: String,
+}
+
+impl Handler<MuteRemove> for ChatServer {
+    type Result = bool;
+
+    fn handle(&mut self, msg: MuteRemove, _: &mut Context<Self>) -> bool {
+        self.moderator.mutes.remove(&msg.player).is_some()
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<Mute>")]
+pub struct MutesGet;
+
+impl Handler<MutesGet> for ChatServer {
+    type Result = Vec<Mute>;
+
+    fn handle(&mut self, _: MutesGet, _: &mut Context<Self>) -> Vec<Mute> {
+        self.moderator.mutes()
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<BlockedMessage>")]
+pub struct BlockedGet;
+
+impl Handler<BlockedGet> for ChatServer {
+    type Result = Vec<BlockedMessage>;
+
+    fn handle(&mut self, _: BlockedGet, _: &mut Context<Self>) -> Vec<BlockedMessage> {
+        self.moderator.blocked.iter().cloned().collect()
+    }
+}
+
+/// 设置频道慢速模式, seconds为0时关闭
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SlowModeSet {
+    pub channel: String,
+    pub seconds: u64,
+}
+
+impl Handler<SlowModeSet> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SlowModeSet, _: &mut Context<Self>) {
+        let moderator = &mut self.moderator;
+        moderator
+            .last_sent
+            .retain(|(channel, _), _| *channel != msg.channel);
+        if msg.seconds == 0 {
+            moderator.slow_mode.remove(&msg.channel);
+        } else {
+            moderator
+                .slow_mode
+                .insert(msg.channel, Duration::from_secs(msg.seconds));
+        }
+    }
+}
+
+/// 禁言请求
+/// player: 玩家名
+/// duration: 禁言时长(秒), 须大于0, 不填为永久禁言
+/// reason: 禁言原因
+#[derive(Deserialize)]
+pub struct MuteRequest {
+    pub player: String,
+    #[serde(default)]
+    pub duration: Option<i64>,
+    #[serde(default)]
+    pub reason: String,
+}
+
+// 禁言玩家, 禁言只保存在内存中, 重启后失效
+pub async fn mute_player(
+    srv: web::Data<Addr<ChatServer>>,
+    request: web::Json<MuteRequest>,
+    token_path: web::Path<String>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    if token_path.as_str() != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let request = request.into_inner();
+    let until = match request.duration {
+        None => None,
+        Some(duration) => {
+            let until = chrono::Local::now().timestamp().checked_add(duration);
+            match until {
+                Some(until) if duration > 0 => Some(until),
+                _ => {
+                    return HttpResponse::BadRequest().json(ResponseMessage {
+                        r#type: "error".to_string(),
+                        message: "禁言时长无效".to_string(),
+                    })
+                }
+            }
+        }
+    };
+    srv.do_send(MuteAdd(Mute {
+        player: request.player,
+        until,
+        reason: request.reason,
+    }));
+    HttpResponse::Ok().json(ResponseMessage {
+        r#type: "success".to_string(),
+        message: "禁言成功".to_string(),
+    })
+}
+
+// 解除禁言
+pub async fn unmute_player(
+    srv: web::Data<Addr<ChatServer>>,
+    path: web::Path<(String, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (player, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    match srv.send(MuteRemove { player }).await {
+        Ok(true) => HttpResponse::Ok().json(ResponseMessage {
+            r#type: "success".to_string(),
+            message: "已解除禁言".to_string(),
+        }),
+        _ => HttpResponse::NotFound().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "玩家未被禁言".to_string(),
+        }),
+    }
+}
+
+// 获取禁言列表
+pub async fn mutes_get(
+    srv: web::Data<Addr<ChatServer>>,
+    token_path: web::Path<String>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    if token_path.as_str() != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    match srv.send(MutesGet).await {
+        Ok(mutes) => HttpResponse::Ok().json(mutes),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// 获取拦截记录
+pub async fn blocked_get(
+    srv: web::Data<Addr<ChatServer>>,
+    token_path: web::Path<String>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    if token_path.as_str() != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    match srv.send(BlockedGet).await {
+        Ok(blocked) => HttpResponse::Ok().json(blocked),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// 慢速模式请求
+/// seconds: 同一发送者两条消息的最小间隔(秒), 0为关闭
+#[derive(Deserialize)]
+pub struct SlowModeRequest {
+    pub seconds: u64,
+}
+
+// 设置频道慢速模式
+pub async fn slow_mode_set(
+    srv: web::Data<Addr<ChatServer>>,
+    path: web::Path<(String, String)>,
+    request: web::Json<SlowModeRequest>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (channel, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    if !chatserver::is_valid_channel(&channel) {
+        return HttpResponse::BadRequest().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "频道名非法".to_string(),
+        });
+    }
+    srv.do_send(SlowModeSet {
+        channel,
+        seconds: request.seconds,
+    });
+    HttpResponse::Ok().json(ResponseMessage {
+        r#type: "success".to_string(),
+        message: "设置成功".to_string(),
+    })
+}
@@ -0,0 +1,664 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use serde::{Deserialize, Serialize};
+use tokio::{fs::File, io::AsyncReadExt};
+
+use crate::sql::nbt_revision::NbtMeta;
+
+/// 解压后nbt数据的最大字节数
+const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+/// 复合标签和列表的最大嵌套深度, 与Java版一致
+const MAX_DEPTH: usize = 512;
+
+const TAG_END: u8 = 0;
+const TAG_COMPOUND: u8 = 10;
+
+/// nbt标签, 序列化为对应的json值
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(HashMap<String, Tag>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    /// 复合标签中的子标签
+    pub fn get(&self, key: &str) -> Option<&Tag> {
+        match self {
+            Tag::Compound(map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    /// 整数类标签的值
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Tag::Byte(v) => Some(v as i64),
+            Tag::Short(v) => Some(v as i64),
+            Tag::Int(v) => Some(v as i64),
+            Tag::Long(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// 数值类标签的值
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Tag::Float(v) => Some(v as f64),
+            Tag::Double(v) => Some(v),
+            _ => self.as_i64().map(|v| v as f64),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Tag::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> &[Tag] {
+        match self {
+            Tag::List(v) => v,
+            _ => &[],
+        }
+    }
+}
+
+/// nbt字节序: Java版为大端, 基岩版为小端
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NbtFormat {
+    Java,
+    Bedrock,
+}
+
+impl NbtFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NbtFormat::Java => "java",
+            NbtFormat::Bedrock => "bedrock",
+        }
+    }
+}
+
+/// nbt文件的压缩方式
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NbtCompression {
+    None,
+    Gzip,
+    Zlib,
+}
+
+impl NbtCompression {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NbtCompression::None => "none",
+            NbtCompression::Gzip => "gzip",
+            NbtCompression::Zlib => "zlib",
+        }
+    }
+}
+
+/// 解析后的nbt文件, 根标签必须是复合标签
+#[derive(Debug, Clone)]
+pub struct Nbt {
+    pub format: NbtFormat,
+    pub compression: NbtCompression,
+    pub root: Tag,
+}
+
+impl Nbt {
+    /// Java版数据版本(DataVersion), 基岩版没有该字段
+    pub fn data_version(&self) -> Option<i32> {
+        self.root
+            .get("DataVersion")
+            .and_then(Tag::as_i64)
+            .map(|v| v as i32)
+    }
+
+    /// 保存到修订记录的元数据
+    pub fn meta(&self) -> NbtMeta {
+        NbtMeta {
+            format: self.format.as_str().to_string(),
+            compression: self.compression.as_str().to_string(),
+            data_version: self.data_version(),
+        }
+    }
+}
+
+/// 解析nbt文件, 自动识别gzip/zlib压缩和字节序
+/// 数据不完整、有多余字节或根标签不是复合标签时返回错误
+pub fn parse(bytes: &[u8]) -> Result<Nbt, &'static str> {
+    if bytes.is_empty() {
+        return Err("nbt数据为空");
+    }
+    let (compression, decompressed) = decompress(bytes)?;
+    let data = decompressed.as_deref().unwrap_or(bytes);
+    // Java版文件通常以gzip压缩, 基岩版通常不压缩, 按此决定优先尝试的字节序
+    let order = match compression {
+        NbtCompression::Gzip => [NbtFormat::Java, NbtFormat::Bedrock],
+        _ => [NbtFormat::Bedrock, NbtFormat::Java],
+    };
+    let mut first_err = None;
+    for format in order {
+        let data = match format {
+            NbtFormat::Bedrock => strip_bedrock_header(data),
+            NbtFormat::Java => data,
+        };
+        match Reader::new(data, format).read_root() {
+            Ok(root) => {
+                return Ok(Nbt {
+                    format,
+                    compression,
+                    root,
+                })
+            }
+            Err(err) => {
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+    Err(first_err.unwrap_or("nbt数据格式错误"))
+}
+
+/// 按文件头识别压缩方式并解压, 未压缩时返回None
+fn decompress(bytes: &[u8]) -> Result<(NbtCompression, Option<Vec<u8>>), &'static str> {
+    let compression = match bytes {
+        [0x1f, 0x8b, ..] => NbtCompression::Gzip,
+        [0x78, b, ..] if (0x7800 | *b as u16).is_multiple_of(31) => NbtCompression::Zlib,
+        _ => return Ok((NbtCompression::None, None)),
+    };
+    let mut data = Vec::new();
+    let reader: Box<dyn Read> = match compression {
+        NbtCompression::Gzip => Box::new(GzDecoder::new(bytes)),
+        _ => Box::new(ZlibDecoder::new(bytes)),
+    };
+    reader
+        .take(MAX_DECOMPRESSED_SIZE + 1)
+        .read_to_end(&mut data)
+        .map_err(|_| "nbt数据解压失败")?;
+    if data.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err("解压后的nbt数据超过大小限制");
+    }
+    Ok((compression, Some(data)))
+}
+
+/// 基岩版level.dat等文件带有8字节文件头: 4字节存储版本 + 4字节数据长度(小端)
+fn strip_bedrock_header(data: &[u8]) -> &[u8] {
+    match data {
+        [_, _, _, _, a, b, c, d, TAG_COMPOUND, ..]
+            if u32::from_le_bytes([*a, *b, *c, *d]) as usize == data.len() - 8 =>
+        {
+            &data[8..]
+        }
+        _ => data,
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    little: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8], format: NbtFormat) -> Reader<'a> {
+        Reader {
+            buf,
+            pos: 0,
+            little: format == NbtFormat::Bedrock,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        if len > self.remaining() {
+            return Err("nbt数据不完整");
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], &'static str> {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(self.take(N)?);
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn i16(&mut self) -> Result<i16, &'static str> {
+        let bytes = self.array()?;
+        Ok(match self.little {
+            true => i16::from_le_bytes(bytes),
+            false => i16::from_be_bytes(bytes),
+        })
+    }
+
+    fn i32(&mut self) -> Result<i32, &'static str> {
+        let bytes = self.array()?;
+        Ok(match self.little {
+            true => i32::from_le_bytes(bytes),
+            false => i32::from_be_bytes(bytes),
+        })
+    }
+
+    fn i64(&mut self) -> Result<i64, &'static str> {
+        let bytes = self.array()?;
+        Ok(match self.little {
+            true => i64::from_le_bytes(bytes),
+            false => i64::from_be_bytes(bytes),
+        })
+    }
+
+    /// 数组和列表的长度, 超过剩余数据能容纳的元素数时返回错误, 避免按伪造的长度分配内存
+    fn len(&mut self, elem_size: usize) -> Result<usize, &'static str> {
+        let len = self.i32()?;
+        if len < 0 {
+            return Err("nbt数组长度为负数");
+        }
+        let len = len as usize;
+        if len.saturating_mul(elem_size) > self.remaining() {
+            return Err("nbt数据不完整");
+        }
+        Ok(len)
+    }
+
+    /// 字符串, Java版使用的modified UTF-8按有损方式转换
+    fn string(&mut self) -> Result<String, &'static str> {
+        let len = self.i16()? as u16 as usize;
+        let bytes = self.take(len)?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    /// 读取根标签, 根标签名通常为空, 不保留
+    fn read_root(mut self) -> Result<Tag, &'static str> {
+        if self.u8()? != TAG_COMPOUND {
+            return Err("nbt根标签不是复合标签");
+        }
+        self.string()?;
+        let root = self.payload(TAG_COMPOUND, 0)?;
+        if self.remaining() != 0 {
+            return Err("nbt数据末尾有多余字节");
+        }
+        Ok(root)
+    }
+
+    fn payload(&mut self, id: u8, depth: usize) -> Result<Tag, &'static str> {
+        Ok(match id {
+            1 => Tag::Byte(self.u8()? as i8),
+            2 => Tag::Short(self.i16()?),
+            3 => Tag::Int(self.i32()?),
+            4 => Tag::Long(self.i64()?),
+            5 => Tag::Float(f32::from_bits(self.i32()? as u32)),
+            6 => Tag::Double(f64::from_bits(self.i64()? as u64)),
+            7 => {
+                let len = self.len(1)?;
+                Tag::ByteArray(self.take(len)?.iter().map(|b| *b as i8).collect())
+            }
+            8 => Tag::String(self.string()?),
+            9 => {
+                if depth >= MAX_DEPTH {
+                    return Err("nbt嵌套层数过多");
+                }
+                let elem = self.u8()?;
+                let len = self.len(1)?;
+                if elem == TAG_END && len > 0 {
+                    return Err("nbt列表类型错误");
+                }
+                let mut list = Vec::with_capacity(len);
+                for _ in 0..len {
+                    list.push(self.payload(elem, depth + 1)?);
+                }
+                Tag::List(list)
+            }
+            TAG_COMPOUND => {
+                if depth >= MAX_DEPTH {
+                    return Err("nbt嵌套层数过多");
+                }
+                let mut map = HashMap::new();
+                loop {
+                    let id = self.u8()?;
+                    if id == TAG_END {
+                        break;
+                    }
+                    let name = self.string()?;
+                    map.insert(name, self.payload(id, depth + 1)?);
+                }
+                Tag::Compound(map)
+            }
+            11 => {
+                let len = self.len(4)?;
+                let mut list = Vec::with_capacity(len);
+                for _ in 0..len {
+                    list.push(self.i32()?);
+                }
+                Tag::IntArray(list)
+            }
+            12 => {
+                let len = self.len(8)?;
+                let mut list = Vec::with_capacity(len);
+                for _ in 0..len {
+                    list.push(self.i64()?);
+                }
+                Tag::LongArray(list)
+            }
+            _ => return Err("未知的nbt标签类型"),
+        })
+    }
+}
+
+/// 元数据文件路径: {path}.meta
+pub fn meta_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".meta");
+    path.with_file_name(name)
+}
+
+/// 与玩家nbt一起保存的元数据, sha256与文件当前的校验和不一致时视为过期
+#[derive(Serialize, Deserialize)]
+struct SavedMeta {
+    sha256: String,
+    #[serde(flatten)]
+    meta: NbtMeta,
+}
+
+/// 保存玩家nbt的元数据, 供actor替换文件后同步调用
+/// 写入失败不影响文件本身, 读取时会重新解析
+pub fn save_meta_sync(path: &Path, sha256: &str, meta: &NbtMeta) {
+    let saved = SavedMeta {
+        sha256: sha256.to_owned(),
+        meta: meta.clone(),
+    };
+    if let Ok(bytes) = serde_json::to_vec(&saved) {
+        let _ = std::fs::write(meta_path(path), bytes);
+    }
+}
+
+/// 读取玩家nbt的元数据, 没有记录或记录已过期时从打开的文件重新解析并保存
+/// file与sha256对应同一份内容, 文件无法解析时返回None
+pub async fn load_meta(path: &Path, sha256: &str, file: &mut File) -> Option<NbtMeta> {
+    let saved = tokio::fs::read(meta_path(path))
+        .await
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<SavedMeta>(&bytes).ok());
+    if let Some(saved) = saved.filter(|saved| saved.sha256 == sha256) {
+        return Some(saved.meta);
+    }
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).await.ok()?;
+    let meta = parse(&bytes).ok()?.meta();
+    let saved = SavedMeta {
+        sha256: sha256.to_owned(),
+        meta: meta.clone(),
+    };
+    if let Ok(bytes) = serde_json::to_vec(&saved) {
+        let _ = tokio::fs::write(meta_path(path), bytes).await;
+    }
+    Some(meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    /// 按指定字节序编码测试数据
+    struct Writer {
+        buf: Vec<u8>,
+        little: bool,
+    }
+
+    impl Writer {
+        fn new(format: NbtFormat) -> Writer {
+            Writer {
+                buf: Vec::new(),
+                little: format == NbtFormat::Bedrock,
+            }
+        }
+
+        fn u8(&mut self, v: u8) -> &mut Writer {
+            self.buf.push(v);
+            self
+        }
+
+        fn i16(&mut self, v: i16) -> &mut Writer {
+            let bytes = match self.little {
+                true => v.to_le_bytes(),
+                false => v.to_be_bytes(),
+            };
+            self.buf.extend_from_slice(&bytes);
+            self
+        }
+
+        fn i32(&mut self, v: i32) -> &mut Writer {
+            let bytes = match self.little {
+                true => v.to_le_bytes(),
+                false => v.to_be_bytes(),
+            };
+            self.buf.extend_from_slice(&bytes);
+            self
+        }
+
+        fn i64(&mut self, v: i64) -> &mut Writer {
+            let bytes = match self.little {
+                true => v.to_le_bytes(),
+                false => v.to_be_bytes(),
+            };
+            self.buf.extend_from_slice(&bytes);
+            self
+        }
+
+        fn string(&mut self, v: &str) -> &mut Writer {
+            self.i16(v.len() as i16);
+            self.buf.extend_from_slice(v.as_bytes());
+            self
+        }
+
+        /// 带名称的标签头
+        fn tag(&mut self, id: u8, name: &str) -> &mut Writer {
+            self.u8(id).string(name)
+        }
+    }
+
+    /// 包含所有标签类型的玩家数据
+    fn player(format: NbtFormat) -> Vec<u8> {
+        let mut w = Writer::new(format);
+        w.tag(TAG_COMPOUND, "");
+        w.tag(3, "DataVersion").i32(3465);
+        w.tag(1, "OnGround").u8(1);
+        w.tag(2, "Fire").i16(-20);
+        w.tag(4, "UUIDMost").i64(-42);
+        w.tag(5, "Health").i32(20.0f32.to_bits() as i32);
+        w.tag(8, "Name").string("Steve");
+        w.tag(9, "Pos").u8(6).i32(2);
+        w.i64(1.5f64.to_bits() as i64)
+            .i64((-3.0f64).to_bits() as i64);
+        w.tag(7, "Bytes").i32(2).u8(1).u8(0xff);
+        w.tag(11, "Ints").i32(2).i32(7).i32(-7);
+        w.tag(12, "Longs").i32(1).i64(i64::MAX);
+        w.tag(9, "Empty").u8(TAG_END).i32(0);
+        w.tag(TAG_COMPOUND, "Abilities");
+        w.tag(1, "flying").u8(0);
+        w.u8(TAG_END);
+        w.u8(TAG_END);
+        w.buf
+    }
+
+    fn player_tag() -> Tag {
+        let abilities = HashMap::from([("flying".to_string(), Tag::Byte(0))]);
+        Tag::Compound(HashMap::from([
+            ("DataVersion".to_string(), Tag::Int(3465)),
+            ("OnGround".to_string(), Tag::Byte(1)),
+            ("Fire".to_string(), Tag::Short(-20)),
+            ("UUIDMost".to_string(), Tag::Long(-42)),
+            ("Health".to_string(), Tag::Float(20.0)),
+            ("Name".to_string(), Tag::String("Steve".to_string())),
+            (
+                "Pos".to_string(),
+                Tag::List(vec![Tag::Double(1.5), Tag::Double(-3.0)]),
+            ),
+            ("Bytes".to_string(), Tag::ByteArray(vec![1, -1])),
+            ("Ints".to_string(), Tag::IntArray(vec![7, -7])),
+            ("Longs".to_string(), Tag::LongArray(vec![i64::MAX])),
+            ("Empty".to_string(), Tag::List(vec![])),
+            ("Abilities".to_string(), Tag::Compound(abilities)),
+        ]))
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read(bytes: &[u8], format: NbtFormat) -> Result<Tag, &'static str> {
+        Reader::new(bytes, format).read_root()
+    }
+
+    #[test]
+    fn parses_java_gzip() {
+        let nbt = parse(&gzip(&player(NbtFormat::Java))).unwrap();
+        assert_eq!(nbt.format, NbtFormat::Java);
+        assert_eq!(nbt.compression, NbtCompression::Gzip);
+        assert_eq!(nbt.root, player_tag());
+        assert_eq!(nbt.data_version(), Some(3465));
+    }
+
+    #[test]
+    fn parses_bedrock_little_endian() {
+        let bytes = player(NbtFormat::Bedrock);
+        let nbt = parse(&bytes).unwrap();
+        assert_eq!(nbt.format, NbtFormat::Bedrock);
+        assert_eq!(nbt.compression, NbtCompression::None);
+        assert_eq!(nbt.root, player_tag());
+
+        // level.dat的8字节文件头
+        let mut with_header = vec![10, 0, 0, 0];
+        with_header.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        with_header.extend_from_slice(&bytes);
+        let nbt = parse(&with_header).unwrap();
+        assert_eq!(nbt.format, NbtFormat::Bedrock);
+        assert_eq!(nbt.root, player_tag());
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        assert_eq!(parse(&[]).unwrap_err(), "nbt数据为空");
+        for format in [NbtFormat::Java, NbtFormat::Bedrock] {
+            let bytes = player(format);
+            for len in 1..bytes.len() {
+                assert!(parse(&bytes[..len]).is_err(), "{:?} {}", format, len);
+            }
+        }
+        let compressed = gzip(&player(NbtFormat::Java));
+        assert!(parse(&compressed[..compressed.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut bytes = player(NbtFormat::Java);
+        bytes.push(0);
+        assert_eq!(
+            read(&bytes, NbtFormat::Java).unwrap_err(),
+            "nbt数据末尾有多余字节"
+        );
+        assert!(parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_lengths() {
+        for format in [NbtFormat::Java, NbtFormat::Bedrock] {
+            let mut w = Writer::new(format);
+            w.tag(TAG_COMPOUND, "").tag(7, "Bytes").i32(-1).u8(TAG_END);
+            assert_eq!(read(&w.buf, format).unwrap_err(), "nbt数组长度为负数");
+            assert!(parse(&w.buf).is_err());
+
+            // 伪造的长度不会按长度预先分配内存
+            for (id, elem) in [(11, None), (12, None), (9, Some(TAG_COMPOUND))] {
+                let mut w = Writer::new(format);
+                w.tag(TAG_COMPOUND, "").tag(id, "Huge");
+                if let Some(elem) = elem {
+                    w.u8(elem);
+                }
+                w.i32(i32::MAX).u8(TAG_END);
+                assert_eq!(read(&w.buf, format).unwrap_err(), "nbt数据不完整");
+                assert!(parse(&w.buf).is_err());
+            }
+
+            let mut w = Writer::new(format);
+            w.tag(TAG_COMPOUND, "").tag(9, "List").u8(TAG_END).i32(1);
+            w.u8(TAG_END);
+            assert_eq!(read(&w.buf, format).unwrap_err(), "nbt列表类型错误");
+        }
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        let nested = |depth: usize| {
+            let mut w = Writer::new(NbtFormat::Java);
+            w.tag(TAG_COMPOUND, "");
+            for _ in 0..depth {
+                w.tag(TAG_COMPOUND, "a");
+            }
+            for _ in 0..=depth {
+                w.u8(TAG_END);
+            }
+            w.buf
+        };
+        assert!(read(&nested(MAX_DEPTH - 1), NbtFormat::Java).is_ok());
+        assert_eq!(
+            read(&nested(MAX_DEPTH), NbtFormat::Java).unwrap_err(),
+            "nbt嵌套层数过多"
+        );
+        assert!(parse(&nested(100_000)).is_err());
+
+        let mut w = Writer::new(NbtFormat::Bedrock);
+        w.tag(TAG_COMPOUND, "").tag(9, "a");
+        for _ in 0..MAX_DEPTH {
+            w.u8(9).i32(1);
+        }
+        w.u8(TAG_END).i32(0).u8(TAG_END);
+        assert_eq!(
+            read(&w.buf, NbtFormat::Bedrock).unwrap_err(),
+            "nbt嵌套层数过多"
+        );
+    }
+
+    #[test]
+    fn rejects_non_compound_root() {
+        for format in [NbtFormat::Java, NbtFormat::Bedrock] {
+            let mut w = Writer::new(format);
+            w.tag(3, "").i32(1);
+            assert_eq!(read(&w.buf, format).unwrap_err(), "nbt根标签不是复合标签");
+            assert!(parse(&w.buf).is_err());
+            assert!(parse(&gzip(&w.buf)).is_err());
+        }
+    }
+}
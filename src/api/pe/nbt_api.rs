@@ -0,0 +1,572 @@
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+
+use super::{
+    nbt::{self, Nbt, NbtFormat, Tag},
+    player_api::ResponseMessage,
+    storage::StorageKey,
+};
+
+/// 物品
+/// slot: 槽位
+/// id: 物品id, Java版为id, 基岩版为Name
+/// damage: 耐久损耗, 没有时为None
+/// tag: 物品的附加数据(附魔、自定义名等), Java版1.20.5起为components
+#[derive(Serialize, Debug)]
+pub struct ItemSummary<'a> {
+    pub slot: Option<i64>,
+    pub id: &'a str,
+    pub count: i64,
+    pub damage: Option<i64>,
+    pub tag: Option<&'a Tag>,
+}
+
+/// 经验
+/// level: 等级
+/// progress: 当前等级的进度(0~1)
+/// total: 总经验, 基岩版没有该字段
+#[derive(Serialize, Debug)]
+pub struct XpSummary {
+    pub level: Option<i64>,
+    pub progress: Option<f64>,
+    pub total: Option<i64>,
+}
+
+/// 生命值, max只有基岩版记录
+#[derive(Serialize, Debug)]
+pub struct HealthSummary {
+    pub current: Option<f64>,
+    pub max: Option<f64>,
+}
+
+/// 位置
+/// dimension: 维度, 基岩版的维度id转换为对应的维度名
+#[derive(Serialize, Debug)]
+pub struct PositionSummary {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub yaw: Option<f64>,
+    pub pitch: Option<f64>,
+    pub dimension: Option<String>,
+}
+
+/// 状态效果
+/// id: 效果id, 旧版本Java和基岩版为数字id
+/// duration: 剩余时间(tick)
+#[derive(Serialize, Debug)]
+pub struct EffectSummary {
+    pub id: String,
+    pub amplifier: i64,
+    pub duration: i64,
+}
+
+/// 从玩家nbt中提取的常用数据
+#[derive(Serialize, Debug)]
+pub struct PlayerNbtSummary<'a> {
+    pub format: NbtFormat,
+    pub inventory: Vec<ItemSummary<'a>>,
+    pub ender_chest: Vec<ItemSummary<'a>>,
+    pub xp: XpSummary,
+    pub health: HealthSummary,
+    pub position: Option<PositionSummary>,
+    pub effects: Vec<EffectSummary>,
+}
+
+/// 可以单独查询的数据项, 与PlayerNbtSummary的字段对应
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Inventory,
+    EnderChest,
+    Xp,
+    Health,
+    Position,
+    Effects,
+}
+
+impl Section {
+    const ALL: [Section; 6] = [
+        Section::Inventory,
+        Section::EnderChest,
+        Section::Xp,
+        Section::Health,
+        Section::Position,
+        Section::Effects,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Section::Inventory => "inventory",
+            Section::EnderChest => "ender_chest",
+            Section::Xp => "xp",
+            Section::Health => "health",
+            Section::Position => "position",
+            Section::Effects => "effects",
+        }
+    }
+
+    fn parse(section: &str) -> Option<Section> {
+        Section::ALL
+            .into_iter()
+            .find(|item| item.as_str() == section)
+    }
+}
+
+impl<'a> PlayerNbtSummary<'a> {
+    pub fn new(nbt: &'a Nbt) -> PlayerNbtSummary<'a> {
+        let root = &nbt.root;
+        let java = nbt.format == NbtFormat::Java;
+        let items = |key: &str| -> Vec<ItemSummary<'a>> {
+            root.get(key)
+                .map(Tag::as_list)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|item| item_summary(item, java))
+                .collect()
+        };
+        let ender_chest = match java {
+            true => items("EnderItems"),
+            false => items("EnderChestInventory"),
+        };
+        let xp = match java {
+            true => XpSummary {
+                level: get_i64(root, "XpLevel"),
+                progress: get_f64(root, "XpP"),
+                total: get_i64(root, "XpTotal"),
+            },
+            false => XpSummary {
+                level: get_i64(root, "PlayerLevel"),
+                progress: get_f64(root, "PlayerLevelProgress"),
+                total: None,
+            },
+        };
+        PlayerNbtSummary {
+            format: nbt.format,
+            inventory: items("Inventory"),
+            ender_chest,
+            xp,
+            health: health_summary(root, java),
+            position: position_summary(root, java),
+            effects: effects_summary(root),
+        }
+    }
+}
+
+fn get_i64(tag: &Tag, key: &str) -> Option<i64> {
+    tag.get(key).and_then(Tag::as_i64)
+}
+
+fn get_f64(tag: &Tag, key: &str) -> Option<f64> {
+    tag.get(key).and_then(Tag::as_f64)
+}
+
+fn item_summary(item: &Tag, java: bool) -> Option<ItemSummary<'_>> {
+    let id = match java {
+        true => item.get("id"),
+        false => item.get("Name"),
+    }
+    .and_then(Tag::as_str)?;
+    // 基岩版空槽位的物品名为空
+    if id.is_empty() {
+        return None;
+    }
+    // Java版1.20.5起数量为count, 附加数据为components
+    let count = get_i64(item, "Count")
+        .or_else(|| get_i64(item, "count"))
+        .unwrap_or(1);
+    let tag = item.get("tag").or_else(|| item.get("components"));
+    let damage = match java {
+        true => {
+            tag.and_then(|tag| get_i64(tag, "Damage").or_else(|| get_i64(tag, "minecraft:damage")))
+        }
+        false => get_i64(item, "Damage"),
+    };
+    Some(ItemSummary {
+        slot: get_i64(item, "Slot"),
+        id,
+        count,
+        damage,
+        tag,
+    })
+}
+
+fn health_summary(root: &Tag, java: bool) -> HealthSummary {
+    if java {
+        return HealthSummary {
+            current: get_f64(root, "Health"),
+            max: None,
+        };
+    }
+    // 基岩版生命值保存在属性列表中
+    let health = root
+        .get("Attributes")
+        .map(Tag::as_list)
+        .unwrap_or_default()
+        .iter()
+        .find(|attr| attr.get("Name").and_then(Tag::as_str) == Some("minecraft:health"));
+    HealthSummary {
+        current: health.and_then(|attr| get_f64(attr, "Current")),
+        max: health.and_then(|attr| get_f64(attr, "Max")),
+    }
+}
+
+fn position_summary(root: &Tag, java: bool) -> Option<PositionSummary> {
+    let pos = root.get("Pos").map(Tag::as_list).unwrap_or_default();
+    let [x, y, z] = pos else {
+        return None;
+    };
+    let rotation = root.get("Rotation").map(Tag::as_list).unwrap_or_default();
+    let dimension = match java {
+        true => root
+            .get("Dimension")
+            .and_then(Tag::as_str)
+            .map(str::to_owned),
+        false => get_i64(root, "DimensionId").map(|id| match id {
+            0 => "minecraft:overworld".to_string(),
+            1 => "minecraft:the_nether".to_string(),
+            2 => "minecraft:the_end".to_string(),
+            id => id.to_string(),
+        }),
+    };
+    Some(PositionSummary {
+        x: x.as_f64()?,
+        y: y.as_f64()?,
+        z: z.as_f64()?,
+        yaw: rotation.first().and_then(Tag::as_f64),
+        pitch: rotation.get(1).and_then(Tag::as_f64),
+        dimension,
+    })
+}
+
+fn effects_summary(root: &Tag) -> Vec<EffectSummary> {
+    // Java版1.20.2起为active_effects, 之前和基岩版为ActiveEffects
+    let effects = root
+        .get("active_effects")
+        .or_else(|| root.get("ActiveEffects"))
+        .map(Tag::as_list)
+        .unwrap_or_default();
+    effects
+        .iter()
+        .filter_map(|effect| {
+            let id = match effect.get("id").or_else(|| effect.get("Id"))? {
+                Tag::String(id) => id.clone(),
+                id => id.as_i64()?.to_string(),
+            };
+            Some(EffectSummary {
+                id,
+                amplifier: get_i64(effect, "amplifier")
+                    .or_else(|| get_i64(effect, "Amplifier"))
+                    .unwrap_or(0),
+                duration: get_i64(effect, "duration")
+                    .or_else(|| get_i64(effect, "Duration"))
+                    .unwrap_or(0),
+            })
+        })
+        .collect()
+}
+
+/// 读取并解析玩家当前的nbt文件
+async fn read_player_nbt(player: &str) -> Result<Nbt, HttpResponse> {
+    let not_found = || {
+        HttpResponse::NotFound().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "文件不存在".to_string(),
+        })
+    };
+    let player = StorageKey::player(player).map_err(|message| {
+        HttpResponse::BadRequest().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: message.to_string(),
+        })
+    })?;
+    let bytes = tokio::fs::read(player.path())
+        .await
+        .map_err(|_| not_found())?;
+    nbt::parse(&bytes).map_err(|message| {
+        HttpResponse::UnprocessableEntity().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: message.to_string(),
+        })
+    })
+}
+
+// 查看玩家的背包、末影箱、经验、生命值、位置和状态效果
+pub async fn player_nbt_inspect(
+    path: web::Path<(String, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (player, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    match read_player_nbt(&player).await {
+        Ok(nbt) => HttpResponse::Ok().json(PlayerNbtSummary::new(&nbt)),
+        Err(response) => response,
+    }
+}
+
+// 查看玩家nbt中的单项数据
+pub async fn player_nbt_inspect_section(
+    path: web::Path<(String, String, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (player, section, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let Some(section) = Section::parse(&section) else {
+        let sections: Vec<_> = Section::ALL.into_iter().map(Section::as_str).collect();
+        return HttpResponse::BadRequest().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: format!("未知的数据项, 可选: {}", sections.join(", ")),
+        });
+    };
+    let nbt = match read_player_nbt(&player).await {
+        Ok(nbt) => nbt,
+        Err(response) => return response,
+    };
+    let summary = PlayerNbtSummary::new(&nbt);
+    match section {
+        Section::Inventory => HttpResponse::Ok().json(summary.inventory),
+        Section::EnderChest => HttpResponse::Ok().json(summary.ender_chest),
+        Section::Xp => HttpResponse::Ok().json(summary.xp),
+        Section::Health => HttpResponse::Ok().json(summary.health),
+        Section::Position => HttpResponse::Ok().json(summary.position),
+        Section::Effects => HttpResponse::Ok().json(summary.effects),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::api::pe::nbt::NbtCompression;
+
+    fn compound<const N: usize>(entries: [(&str, Tag); N]) -> Tag {
+        Tag::Compound(HashMap::from(
+            entries.map(|(key, tag)| (key.to_string(), tag)),
+        ))
+    }
+
+    fn string(v: &str) -> Tag {
+        Tag::String(v.to_string())
+    }
+
+    fn nbt(format: NbtFormat, root: Tag) -> Nbt {
+        Nbt {
+            format,
+            compression: NbtCompression::None,
+            root,
+        }
+    }
+
+    #[test]
+    fn java_items() {
+        let legacy = compound([
+            ("Slot", Tag::Byte(0)),
+            ("id", string("minecraft:diamond_sword")),
+            ("Count", Tag::Byte(1)),
+            ("tag", compound([("Damage", Tag::Int(12))])),
+        ]);
+        // 1.20.5起的格式
+        let components = compound([
+            ("Slot", Tag::Byte(1)),
+            ("id", string("minecraft:bow")),
+            ("count", Tag::Int(2)),
+            ("components", compound([("minecraft:damage", Tag::Int(3))])),
+        ]);
+        let ender = compound([
+            ("Slot", Tag::Byte(5)),
+            ("id", string("minecraft:stone")),
+            ("count", Tag::Int(64)),
+        ]);
+        let nbt = nbt(
+            NbtFormat::Java,
+            compound([
+                ("Inventory", Tag::List(vec![legacy, components])),
+                ("EnderItems", Tag::List(vec![ender])),
+                ("EnderChestInventory", Tag::List(vec![])),
+            ]),
+        );
+        let summary = PlayerNbtSummary::new(&nbt);
+        let inventory: Vec<_> = summary
+            .inventory
+            .iter()
+            .map(|item| (item.slot, item.id, item.count, item.damage))
+            .collect();
+        assert_eq!(
+            inventory,
+            [
+                (Some(0), "minecraft:diamond_sword", 1, Some(12)),
+                (Some(1), "minecraft:bow", 2, Some(3)),
+            ]
+        );
+        assert!(summary.inventory[1]
+            .tag
+            .is_some_and(|tag| tag.get("minecraft:damage").is_some()));
+        assert_eq!(summary.ender_chest.len(), 1);
+        assert_eq!(summary.ender_chest[0].id, "minecraft:stone");
+        assert_eq!(summary.ender_chest[0].count, 64);
+        assert_eq!(summary.ender_chest[0].damage, None);
+    }
+
+    #[test]
+    fn bedrock_items() {
+        let item = |slot: i8, name: &str, count: i8, damage: i16| {
+            compound([
+                ("Slot", Tag::Byte(slot)),
+                ("Name", string(name)),
+                ("Count", Tag::Byte(count)),
+                ("Damage", Tag::Short(damage)),
+            ])
+        };
+        let nbt = nbt(
+            NbtFormat::Bedrock,
+            compound([
+                (
+                    "Inventory",
+                    Tag::List(vec![item(0, "minecraft:apple", 3, 0), item(1, "", 0, 0)]),
+                ),
+                (
+                    "EnderChestInventory",
+                    Tag::List(vec![item(2, "minecraft:iron_pickaxe", 1, 40)]),
+                ),
+                (
+                    "EnderItems",
+                    Tag::List(vec![item(3, "minecraft:dirt", 1, 0)]),
+                ),
+            ]),
+        );
+        let summary = PlayerNbtSummary::new(&nbt);
+        // 空槽位被跳过
+        assert_eq!(summary.inventory.len(), 1);
+        assert_eq!(summary.inventory[0].id, "minecraft:apple");
+        assert_eq!(summary.inventory[0].count, 3);
+        assert_eq!(summary.ender_chest.len(), 1);
+        assert_eq!(summary.ender_chest[0].id, "minecraft:iron_pickaxe");
+        assert_eq!(summary.ender_chest[0].slot, Some(2));
+        assert_eq!(summary.ender_chest[0].damage, Some(40));
+    }
+
+    #[test]
+    fn health() {
+        let java = nbt(NbtFormat::Java, compound([("Health", Tag::Float(17.5))]));
+        let health = PlayerNbtSummary::new(&java).health;
+        assert_eq!((health.current, health.max), (Some(17.5), None));
+
+        let attribute = |name: &str, current: f32, max: f32| {
+            compound([
+                ("Name", string(name)),
+                ("Current", Tag::Float(current)),
+                ("Max", Tag::Float(max)),
+            ])
+        };
+        let bedrock = nbt(
+            NbtFormat::Bedrock,
+            compound([(
+                "Attributes",
+                Tag::List(vec![
+                    attribute("minecraft:movement", 0.1, 0.1),
+                    attribute("minecraft:health", 8.0, 20.0),
+                ]),
+            )]),
+        );
+        let health = PlayerNbtSummary::new(&bedrock).health;
+        assert_eq!((health.current, health.max), (Some(8.0), Some(20.0)));
+
+        let missing = nbt(NbtFormat::Bedrock, compound([]));
+        let health = PlayerNbtSummary::new(&missing).health;
+        assert_eq!((health.current, health.max), (None, None));
+    }
+
+    #[test]
+    fn position_dimension() {
+        let pos = || Tag::List(vec![Tag::Float(1.0), Tag::Float(64.0), Tag::Float(-2.5)]);
+        let dimension = |id: i32| {
+            let root = compound([("Pos", pos()), ("DimensionId", Tag::Int(id))]);
+            PlayerNbtSummary::new(&nbt(NbtFormat::Bedrock, root))
+                .position
+                .and_then(|position| position.dimension)
+        };
+        assert_eq!(dimension(0).as_deref(), Some("minecraft:overworld"));
+        assert_eq!(dimension(1).as_deref(), Some("minecraft:the_nether"));
+        assert_eq!(dimension(2).as_deref(), Some("minecraft:the_end"));
+        assert_eq!(dimension(7).as_deref(), Some("7"));
+
+        let java = nbt(
+            NbtFormat::Java,
+            compound([
+                (
+                    "Pos",
+                    Tag::List(vec![Tag::Double(0.5), Tag::Double(70.0), Tag::Double(3.0)]),
+                ),
+                (
+                    "Rotation",
+                    Tag::List(vec![Tag::Float(90.0), Tag::Float(-10.0)]),
+                ),
+                ("Dimension", string("minecraft:the_end")),
+            ]),
+        );
+        let position = PlayerNbtSummary::new(&java).position.unwrap();
+        assert_eq!((position.x, position.y, position.z), (0.5, 70.0, 3.0));
+        assert_eq!((position.yaw, position.pitch), (Some(90.0), Some(-10.0)));
+        assert_eq!(position.dimension.as_deref(), Some("minecraft:the_end"));
+
+        // 坐标不完整时没有位置
+        let partial = nbt(
+            NbtFormat::Java,
+            compound([("Pos", Tag::List(vec![Tag::Double(0.5)]))]),
+        );
+        assert!(PlayerNbtSummary::new(&partial).position.is_none());
+    }
+
+    #[test]
+    fn effects() {
+        let java = nbt(
+            NbtFormat::Java,
+            compound([(
+                "active_effects",
+                Tag::List(vec![compound([
+                    ("id", string("minecraft:speed")),
+                    ("amplifier", Tag::Byte(1)),
+                    ("duration", Tag::Int(600)),
+                ])]),
+            )]),
+        );
+        let bedrock = nbt(
+            NbtFormat::Bedrock,
+            compound([(
+                "ActiveEffects",
+                Tag::List(vec![compound([
+                    ("Id", Tag::Byte(1)),
+                    ("Amplifier", Tag::Byte(0)),
+                    ("Duration", Tag::Int(200)),
+                ])]),
+            )]),
+        );
+        let effects = |nbt: &Nbt| -> Vec<(String, i64, i64)> {
+            PlayerNbtSummary::new(nbt)
+                .effects
+                .into_iter()
+                .map(|effect| (effect.id, effect.amplifier, effect.duration))
+                .collect()
+        };
+        assert_eq!(effects(&java), [("minecraft:speed".to_string(), 1, 600)]);
+        assert_eq!(effects(&bedrock), [("1".to_string(), 0, 200)]);
+    }
+
+    #[test]
+    fn sections() {
+        for section in Section::ALL {
+            assert_eq!(Section::parse(section.as_str()), Some(section));
+        }
+        assert_eq!(Section::parse("format"), None);
+    }
+}
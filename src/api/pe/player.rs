@@ -1,23 +1,124 @@
 use std::collections::HashMap;
 
-use actix::{Actor, Context, Handler, Message};
+use actix::{Actor, Addr, Context, Handler, Message};
 use serde::{Deserialize, Serialize};
 
+use crate::sql::player_registry::{PlayerRecord, PlayerRegistrySqlite};
+
+use super::{chatserver::ChatServer, protocol::ServerFrame};
+
 /// 玩家基础数据
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Player {
     /// 消息
-    data: String,
+    pub data: String,
 
     /// 玩家名
-    name: String,
+    pub name: String,
+
+    /// 玩家uuid, 加入时必填, 旧版客户端的聊天和离开消息可以不填
+    #[serde(default)]
+    pub uuid: String,
 
     /// 服务器
-    server: String,
+    pub server: String,
+
+    /// 客户端信息, 加入时可选填写
+    #[serde(flatten)]
+    pub client: ClientInfo,
+}
+
+/// 玩家客户端信息, 未填写的字段不会覆盖已有的值
+/// ip: 客户端ip
+/// platform: 平台/设备
+/// version: 客户端版本
+/// ping: 延迟(毫秒)
+/// metadata: 自定义数据, 按键合并
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ClientInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ping: Option<u32>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, serde_json::Value>,
+}
+
+impl ClientInfo {
+    /// 用新上报的信息更新, 未填写的字段保留原值
+    pub fn update(&mut self, other: ClientInfo) {
+        if other.ip.is_some() {
+            self.ip = other.ip;
+        }
+        if other.platform.is_some() {
+            self.platform = other.platform;
+        }
+        if other.version.is_some() {
+            self.version = other.version;
+        }
+        if other.ping.is_some() {
+            self.ping = other.ping;
+        }
+        self.metadata.extend(other.metadata);
+    }
+}
+
+/// 在线玩家的会话数据
+/// joined_at: 加入网络的时间(unix秒), 切换服务端时不变
+/// last_seen: 最后一次加入或心跳的时间(unix秒)
+#[derive(Serialize, Clone, Debug)]
+pub struct PlayerSession {
+    pub name: String,
+    pub uuid: String,
+    pub server: String,
+    pub joined_at: i64,
+    pub last_seen: i64,
+    #[serde(flatten)]
+    pub client: ClientInfo,
+}
+
+/// 统一uuid格式: 去除首尾空白并转为小写, 32位无连字符的十六进制uuid补全为8-4-4-4-12格式
+/// 玩家列表和经济系统使用同一格式识别玩家
+pub fn normalize_uuid(uuid: &str) -> String {
+    let uuid = uuid.trim().to_ascii_lowercase();
+    if uuid.len() == 32 && uuid.chars().all(|c| c.is_ascii_hexdigit()) {
+        return format!(
+            "{}-{}-{}-{}-{}",
+            &uuid[0..8],
+            &uuid[8..12],
+            &uuid[12..16],
+            &uuid[16..20],
+            &uuid[20..32]
+        );
+    }
+    uuid
+}
+
+/// 玩家在线状态变化事件
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceEvent {
+    /// 加入网络
+    Joined,
+    /// 离开网络
+    Left,
+    /// 切换服务端
+    Switched,
 }
 
 pub struct PlayerManager {
-    players: HashMap<String, Player>,
+    /// uuid -> 玩家会话
+    players: HashMap<String, PlayerSession>,
+    /// 玩家名 -> uuid, 同名玩家以最后加入的为准
+    names: HashMap<String, String>,
+    /// 用于向所有会话广播在线状态变化
+    srv: Addr<ChatServer>,
+    /// 玩家最后已知状态的持久化, 初始化失败时为None
+    store: Option<PlayerRegistrySqlite>,
 }
 
 impl Actor for PlayerManager {
@@ -25,33 +126,280 @@ impl Actor for PlayerManager {
 }
 
 impl PlayerManager {
-    pub fn new() -> PlayerManager {
+    pub fn new(srv: Addr<ChatServer>) -> PlayerManager {
+        let store = match PlayerRegistrySqlite::init() {
+            Ok(store) => Some(store),
+            Err(err) => {
+                log::error!("玩家列表持久化初始化失败: {}", err);
+                None
+            }
+        };
         PlayerManager {
             players: HashMap::new(),
+            names: HashMap::new(),
+            srv,
+            store,
+        }
+    }
+
+    /// 保存玩家的最后已知状态
+    fn persist<'a>(&self, sessions: impl IntoIterator<Item = &'a PlayerSession>, online: bool) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let now = chrono::Local::now().timestamp();
+        let records: Vec<PlayerRecord> = sessions
+            .into_iter()
+            .map(|session| PlayerRecord {
+                uuid: session.uuid.clone(),
+                name: session.name.clone(),
+                server: session.server.clone(),
+                online,
+                last_seen: if online { session.last_seen } else { now },
+            })
+            .collect();
+        if let Err(err) = store.save(&records) {
+            log::error!("玩家状态保存失败: {}", err);
         }
     }
+
+    /// 通过ChatServer广播在线状态变化
+    fn emit(&self, event: PresenceEvent, player: &PlayerSession, from: Option<String>) {
+        self.srv.do_send(Presence {
+            event,
+            player: player.name.clone(),
+            uuid: player.uuid.clone(),
+            server: player.server.clone(),
+            from,
+        });
+    }
 }
 
 impl PlayerManager {
-    /// 添加玩家
+    /// 按uuid或玩家名查找在线玩家, 优先匹配uuid
+    pub fn find(&self, id: &str) -> Option<&PlayerSession> {
+        self.players
+            .get(&normalize_uuid(id))
+            .or_else(|| self.names.get(id).and_then(|uuid| self.players.get(uuid)))
+    }
+    /// 按玩家名前缀查找在线玩家, 不区分大小写, 按玩家名排序
+    pub fn search(&self, prefix: &str, limit: usize) -> Vec<PlayerSession> {
+        let prefix = prefix.to_lowercase();
+        let mut sessions: Vec<PlayerSession> = self
+            .players
+            .values()
+            .filter(|session| session.name.to_lowercase().starts_with(&prefix))
+            .cloned()
+            .collect();
+        sessions.sort_by(|a, b| a.name.cmp(&b.name));
+        sessions.truncate(limit);
+        sessions
+    }
+    /// 添加玩家, uuid需已统一格式
     pub fn add_player(&mut self, player: Player) {
-        self.players.insert(player.clone().name, player);
+        let now = chrono::Local::now().timestamp();
+        let mut session = PlayerSession {
+            name: player.name,
+            uuid: player.uuid,
+            server: player.server,
+            joined_at: now,
+            last_seen: now,
+            client: ClientInfo::default(),
+        };
+        let previous = self.players.remove(&session.uuid);
+        if let Some(previous) = &previous {
+            if previous.name != session.name {
+                self.unindex_name(previous);
+            }
+            session.joined_at = previous.joined_at;
+            session.client = previous.client.clone();
+        }
+        session.client.update(player.client);
+        self.names
+            .insert(session.name.clone(), session.uuid.clone());
+        self.players.insert(session.uuid.clone(), session.clone());
+        self.persist([&session], true);
+        match previous {
+            None => self.emit(PresenceEvent::Joined, &session, None),
+            Some(previous) if previous.server != session.server => {
+                self.emit(PresenceEvent::Switched, &session, Some(previous.server))
+            }
+            Some(_) => {}
+        }
     }
-    /// 移除某个玩家
+    /// 更新服务端上报的玩家心跳, 只更新当前在该服务端的玩家, 返回更新的玩家数
+    pub fn heartbeat(&mut self, server: &str, entries: Vec<HeartbeatEntry>) -> usize {
+        let now = chrono::Local::now().timestamp();
+        let mut updated = Vec::new();
+        for entry in entries {
+            let id = if entry.uuid.is_empty() {
+                &entry.name
+            } else {
+                &entry.uuid
+            };
+            let uuid = match self.find(id) {
+                Some(session) if session.server == server => session.uuid.clone(),
+                _ => continue,
+            };
+            if let Some(session) = self.players.get_mut(&uuid) {
+                session.last_seen = now;
+                session.client.update(entry.client);
+                updated.push(uuid);
+            }
+        }
+        self.persist(
+            updated.iter().filter_map(|uuid| self.players.get(uuid)),
+            true,
+        );
+        updated.len()
+    }
+    /// 用服务端上报的完整玩家列表同步该服务端的在线玩家, 用于游戏服重连后恢复玩家列表
+    /// 不在列表中的玩家视为已离开, 返回同步后该服务端的玩家数
+    pub fn sync(&mut self, server: &str, entries: Vec<RosterEntry>) -> usize {
+        let roster: Vec<Player> = entries
+            .into_iter()
+            .map(|entry| Player {
+                data: String::new(),
+                name: entry.name,
+                uuid: normalize_uuid(&entry.uuid),
+                server: server.to_owned(),
+                client: entry.client,
+            })
+            .filter(|player| !player.uuid.is_empty())
+            .collect();
+        let left: Vec<PlayerSession> = self
+            .players
+            .values()
+            .filter(|session| {
+                session.server == server && !roster.iter().any(|player| player.uuid == session.uuid)
+            })
+            .cloned()
+            .collect();
+        for session in &left {
+            self.players.remove(&session.uuid);
+            self.unindex_name(session);
+            self.emit(PresenceEvent::Left, session, None);
+        }
+        self.persist(&left, false);
+        let count = roster.len();
+        for player in roster {
+            self.add_player(player);
+        }
+        count
+    }
+    /// 查询玩家的最后已知状态, 在线玩家返回当前状态, 离线玩家从持久化记录中查询
+    pub fn record(&self, id: PlayerId) -> Option<PlayerRecord> {
+        let online = match &id {
+            PlayerId::Uuid(uuid) => self.players.get(&normalize_uuid(uuid)),
+            PlayerId::Name(name) => self.names.get(name).and_then(|uuid| self.players.get(uuid)),
+        };
+        if let Some(session) = online {
+            return Some(PlayerRecord {
+                uuid: session.uuid.clone(),
+                name: session.name.clone(),
+                server: session.server.clone(),
+                online: true,
+                last_seen: session.last_seen,
+            });
+        }
+        let store = self.store.as_ref()?;
+        let record = match &id {
+            PlayerId::Uuid(uuid) => store.get_by_uuid(&normalize_uuid(uuid)),
+            PlayerId::Name(name) => store.get_by_name(name),
+        };
+        record.unwrap_or_else(|err| {
+            log::error!("玩家状态查询失败: {}", err);
+            None
+        })
+    }
+    /// 移除某个玩家, 未填uuid时按玩家名查找
+    /// 玩家已切换到其他服务端时忽略原服务端的离开消息
     pub fn remove_player(&mut self, player: Player) {
-        self.players.remove(&player.name);
+        let uuid = if player.uuid.is_empty() {
+            match self.names.get(&player.name) {
+                Some(uuid) => uuid.clone(),
+                None => return,
+            }
+        } else {
+            normalize_uuid(&player.uuid)
+        };
+        let switched = self
+            .players
+            .get(&uuid)
+            .is_some_and(|current| current.server != player.server);
+        if switched {
+            return;
+        }
+        if let Some(removed) = self.players.remove(&uuid) {
+            self.unindex_name(&removed);
+            self.persist([&removed], false);
+            self.emit(PresenceEvent::Left, &removed, None);
+        }
     }
     /// 获取所有玩家名字的列表
     pub fn get_players(&self) -> Vec<String> {
-        self.players.keys().cloned().collect()
+        self.players
+            .values()
+            .map(|player| player.name.clone())
+            .collect()
     }
     /// 移除所有玩家
     pub fn _remove_player_all(&mut self) {
         self.players.clear();
+        self.names.clear();
     }
     /// 删除指定服务端下的所有玩家
     fn remove_players_by_server(&mut self, server: &str) {
-        self.players.retain(|_, player| player.server != server);
+        let mut removed = Vec::new();
+        self.players.retain(|_, player| {
+            if player.server == server {
+                removed.push(player.clone());
+                return false;
+            }
+            true
+        });
+        self.persist(&removed, false);
+        for player in removed {
+            self.unindex_name(&player);
+            self.emit(PresenceEvent::Left, &player, None);
+        }
+    }
+    /// 移除玩家名索引, 索引已指向同名的其他玩家时保留
+    fn unindex_name(&mut self, player: &PlayerSession) {
+        if self.names.get(&player.name) == Some(&player.uuid) {
+            self.names.remove(&player.name);
+        }
+    }
+}
+
+/// 向所有使用JSON帧协议的会话广播玩家在线状态变化
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Presence {
+    pub event: PresenceEvent,
+    pub player: String,
+    pub uuid: String,
+    pub server: String,
+    pub from: Option<String>,
+}
+
+impl Handler<Presence> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Presence, _: &mut Context<Self>) {
+        let text = ServerFrame::Presence {
+            event: msg.event,
+            player: &msg.player,
+            uuid: &msg.uuid,
+            server: &msg.server,
+            from: msg.from.as_deref(),
+        }
+        .to_text();
+        for (id, _) in self.session_names() {
+            if self.is_framed(id) {
+                self.deliver(id, &text);
+            }
+        }
     }
 }
 
@@ -106,3 +454,147 @@ impl Handler<PlayersRemoveByServer> for PlayerManager {
         self.remove_players_by_server(&players_remove_by_server.server);
     }
 }
+
+/// 按uuid或玩家名查询玩家所在的服务端, 玩家不在线时返回None
+#[derive(Message)]
+#[rtype(result = "Option<String>")]
+pub struct PlayerServerGet {
+    pub name: String,
+}
+impl Handler<PlayerServerGet> for PlayerManager {
+    type Result = Option<String>;
+
+    fn handle(&mut self, msg: PlayerServerGet, _: &mut Context<Self>) -> Option<String> {
+        self.find(&msg.name).map(|player| player.server.clone())
+    }
+}
+
+/// 玩家心跳
+/// uuid: 玩家uuid, 不填时按name查找
+#[derive(Deserialize, Debug)]
+pub struct HeartbeatEntry {
+    #[serde(default)]
+    pub uuid: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(flatten)]
+    pub client: ClientInfo,
+}
+
+/// 服务端批量上报玩家心跳, 返回更新的玩家数
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct PlayersHeartbeat {
+    pub server: String,
+    pub players: Vec<HeartbeatEntry>,
+}
+impl Handler<PlayersHeartbeat> for PlayerManager {
+    type Result = usize;
+
+    fn handle(&mut self, msg: PlayersHeartbeat, _: &mut Context<Self>) -> usize {
+        self.heartbeat(&msg.server, msg.players)
+    }
+}
+
+/// 获取在线玩家的会话数据, server不为None时只返回该服务端的玩家
+#[derive(Message)]
+#[rtype(result = "Vec<PlayerSession>")]
+pub struct PlayerSessionsGet {
+    pub server: Option<String>,
+}
+impl Handler<PlayerSessionsGet> for PlayerManager {
+    type Result = Vec<PlayerSession>;
+
+    fn handle(&mut self, msg: PlayerSessionsGet, _: &mut Context<Self>) -> Vec<PlayerSession> {
+        let mut sessions: Vec<PlayerSession> = self
+            .players
+            .values()
+            .filter(|session| {
+                msg.server
+                    .as_ref()
+                    .is_none_or(|server| session.server == *server)
+            })
+            .cloned()
+            .collect();
+        sessions.sort_by(|a, b| a.name.cmp(&b.name));
+        sessions
+    }
+}
+
+/// 查找玩家的方式
+#[derive(Debug)]
+pub enum PlayerId {
+    Uuid(String),
+    Name(String),
+}
+
+/// 按uuid或玩家名精确查找在线玩家
+#[derive(Message)]
+#[rtype(result = "Option<PlayerSession>")]
+pub struct PlayerGet {
+    pub id: PlayerId,
+}
+impl Handler<PlayerGet> for PlayerManager {
+    type Result = Option<PlayerSession>;
+
+    fn handle(&mut self, msg: PlayerGet, _: &mut Context<Self>) -> Option<PlayerSession> {
+        let uuid = match msg.id {
+            PlayerId::Uuid(uuid) => normalize_uuid(&uuid),
+            PlayerId::Name(name) => self.names.get(&name)?.clone(),
+        };
+        self.players.get(&uuid).cloned()
+    }
+}
+
+/// 按玩家名前缀查找在线玩家
+#[derive(Message)]
+#[rtype(result = "Vec<PlayerSession>")]
+pub struct PlayersSearch {
+    pub prefix: String,
+    pub limit: usize,
+}
+impl Handler<PlayersSearch> for PlayerManager {
+    type Result = Vec<PlayerSession>;
+
+    fn handle(&mut self, msg: PlayersSearch, _: &mut Context<Self>) -> Vec<PlayerSession> {
+        self.search(&msg.prefix, msg.limit)
+    }
+}
+
+/// 游戏服同步的玩家
+#[derive(Deserialize, Debug)]
+pub struct RosterEntry {
+    pub name: String,
+    pub uuid: String,
+    #[serde(flatten)]
+    pub client: ClientInfo,
+}
+
+/// 用游戏服上报的完整玩家列表同步该服务端的在线玩家, 返回同步后的玩家数
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct PlayersSync {
+    pub server: String,
+    pub players: Vec<RosterEntry>,
+}
+impl Handler<PlayersSync> for PlayerManager {
+    type Result = usize;
+
+    fn handle(&mut self, msg: PlayersSync, _: &mut Context<Self>) -> usize {
+        self.sync(&msg.server, msg.players)
+    }
+}
+
+/// 查询玩家的最后已知状态, 包括离线玩家
+#[derive(Message)]
+#[rtype(result = "Option<PlayerRecord>")]
+pub struct PlayerRecordGet {
+    pub id: PlayerId,
+}
+impl Handler<PlayerRecordGet> for PlayerManager {
+    type Result = Option<PlayerRecord>;
+
+    fn handle(&mut self, msg: PlayerRecordGet, _: &mut Context<Self>) -> Option<PlayerRecord> {
+        self.record(msg.id)
+    }
+}
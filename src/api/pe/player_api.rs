@@ -1,28 +1,51 @@
 use actix::*;
 
 use actix_web::{
+    http::header::{EntityTag, ETAG},
     web::{self, Json},
-    HttpResponse,
+    HttpRequest, HttpResponse,
 };
 
-use futures_util::StreamExt;
 use log::info;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
-};
 
-use crate::DIR_PATH_PLAYER_NBT;
+use crate::{
+    config::ServerConfig,
+    sql::{
+        mailbox::MailboxSqlite,
+        nbt_revision::{NbtMeta, NbtRevisionSqlite},
+    },
+};
 
 use super::{
     chatserver,
-    player::{Player, PlayerJoin, PlayerLeft, PlayerManager, PlayersGet},
+    file_stream::{receive_to_file, serve_file, upload_tmp_path},
+    integrity::{expected_sha256, open_with_hash, verify, FileMeta, CONTENT_SHA256_HEADER},
+    lease::{NbtCommit, NbtCommitError, NbtWriteCheck},
+    nbt,
+    nbt_api::{player_nbt_inspect, player_nbt_inspect_section},
+    player::{
+        normalize_uuid, HeartbeatEntry, Player, PlayerGet, PlayerId, PlayerJoin, PlayerLeft,
+        PlayerManager, PlayerRecordGet, PlayerSessionsGet, PlayersGet, PlayersHeartbeat,
+        PlayersSearch,
+    },
+    revision::{record_revision, revision_get, revision_rollback, revisions_get, RevisionSnapshot},
+    storage::StorageKey,
+    transfer::{TransferActive, TransferManager},
+    whisper::{deliver_mailbox, post_whisper},
 };
 
+/// 聊天消息发送的频道, 默认为全局频道
+#[derive(Deserialize)]
+pub struct ChatChannel {
+    #[serde(default)]
+    pub channel: Option<String>,
+}
+
 pub async fn post_chat(
     player: web::Json<Player>,
+    chat_channel: web::Query<ChatChannel>,
     srv: web::Data<Addr<chatserver::ChatServer>>,
     token_path: web::Path<String>,
     token: web::Data<String>,
@@ -33,20 +56,43 @@ pub async fn post_chat(
             message: "token错误".to_string(),
         });
     }
-    let msg = chatserver::BroadcastMessage {
+    let channel = chat_channel
+        .into_inner()
+        .channel
+        .unwrap_or_else(|| chatserver::DEFAULT_CHANNEL.to_string());
+    if !chatserver::is_valid_channel(&channel) {
+        return HttpResponse::BadRequest().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "频道名非法".to_string(),
+        });
+    }
+    let msg = chatserver::PlayerChat {
+        channel,
+        player: player.name.clone(),
+        text: player.data.clone(),
         msg: json!(player).to_string(),
     };
-    let _ = srv.as_ref().clone().do_send(msg);
-    // HttpResponse
-    HttpResponse::Ok().json(ResponseMessage {
-        r#type: "success".to_string(),
-        message: "消息发送成功".to_string(),
-    })
+    match srv.send(msg).await {
+        Ok(Ok(())) => HttpResponse::Ok().json(ResponseMessage {
+            r#type: "success".to_string(),
+            message: "消息发送成功".to_string(),
+        }),
+        Ok(Err(reason)) => HttpResponse::Forbidden().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: reason,
+        }),
+        Err(_) => HttpResponse::InternalServerError().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "消息发送失败".to_string(),
+        }),
+    }
 }
 
 pub async fn player_join(
     player: web::Json<Player>,
     players: web::Data<Addr<PlayerManager>>,
+    srv: web::Data<Addr<chatserver::ChatServer>>,
+    mailbox: web::Data<MailboxSqlite>,
     token_path: web::Path<String>,
     token: web::Data<String>,
 ) -> HttpResponse {
@@ -56,9 +102,19 @@ pub async fn player_join(
             message: "token错误".to_string(),
         });
     }
+    let mut player = player.into_inner();
+    player.uuid = normalize_uuid(&player.uuid);
+    if player.uuid.is_empty() {
+        return HttpResponse::BadRequest().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "玩家uuid不能为空".to_string(),
+        });
+    }
     let player_manager = players.as_ref().clone();
-    let player_join = PlayerJoin { player: player.0 };
+    let (name, server) = (player.name.clone(), player.server.clone());
+    let player_join = PlayerJoin { player };
     player_manager.do_send(player_join);
+    deliver_mailbox(&name, &server, &srv, &mailbox).await;
     HttpResponse::Ok().json(ResponseMessage {
         r#type: "success".to_string(),
         message: "玩家加入".to_string(),
@@ -103,32 +159,209 @@ pub async fn players_get(
     }
 }
 
+/// 玩家心跳请求
+/// server: 上报的服务端名
+/// players: 该服务端玩家的心跳
+#[derive(Deserialize)]
+pub struct HeartbeatRequest {
+    pub server: String,
+    pub players: Vec<HeartbeatEntry>,
+}
+
+// 服务端批量上报玩家心跳, 更新最后在线时间、延迟等数据
+pub async fn players_heartbeat(
+    request: web::Json<HeartbeatRequest>,
+    players: web::Data<Addr<PlayerManager>>,
+    token_path: web::Path<String>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    if token_path.as_str() != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let request = request.into_inner();
+    let msg = PlayersHeartbeat {
+        server: request.server,
+        players: request.players,
+    };
+    match players.send(msg).await {
+        Ok(updated) => HttpResponse::Ok().json(ResponseMessage {
+            r#type: "success".to_string(),
+            message: format!("已更新{}个玩家", updated),
+        }),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// 在线玩家查询条件, server为空时返回所有服务端的玩家
+#[derive(Deserialize)]
+pub struct PlayersQuery {
+    #[serde(default)]
+    pub server: Option<String>,
+}
+
+// 获取在线玩家的会话数据
+pub async fn player_sessions_get(
+    query: web::Query<PlayersQuery>,
+    players: web::Data<Addr<PlayerManager>>,
+    token_path: web::Path<String>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    if token_path.as_str() != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let msg = PlayerSessionsGet {
+        server: query.into_inner().server,
+    };
+    match players.send(msg).await {
+        Ok(sessions) => HttpResponse::Ok().json(sessions),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
 //检查玩家是否在线
 pub async fn player_check_online(
-    path: web::Path<String>,
+    path: web::Path<(String, String)>,
     players: web::Data<Addr<PlayerManager>>,
+    token: web::Data<String>,
 ) -> HttpResponse {
-    let player_name = path.as_str();
-    let this = players.as_ref().clone();
-    let msg = PlayersGet {};
-    let players = this.send(msg).await.unwrap();
-    for player in players {
-        if player == player_name {
-            return HttpResponse::Ok().json(ResponseMessage {
-                r#type: "success".to_string(),
-                message: "玩家在线".to_string(),
+    let (player_name, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let msg = PlayerGet {
+        id: PlayerId::Name(player_name),
+    };
+    match players.send(msg).await {
+        Ok(Some(_)) => HttpResponse::Ok().json(ResponseMessage {
+            r#type: "success".to_string(),
+            message: "玩家在线".to_string(),
+        }),
+        _ => HttpResponse::NotFound().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "玩家不在线".to_string(),
+        }),
+    }
+}
+
+/// 玩家查询条件, 三者只能填一个
+/// name: 按玩家名精确查找
+/// uuid: 按uuid精确查找
+/// prefix: 按玩家名前缀查找, 不区分大小写, 最多返回limit个
+#[derive(Deserialize)]
+pub struct LookupQuery {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub uuid: Option<String>,
+    #[serde(default)]
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// 前缀查找默认和最多返回的玩家数
+const LOOKUP_LIMIT: usize = 50;
+
+// 查找在线玩家, 返回所在服务端和会话数据
+// 精确查找返回单个玩家, 不在线时返回404; 前缀查找返回玩家列表
+pub async fn player_lookup(
+    query: web::Query<LookupQuery>,
+    players: web::Data<Addr<PlayerManager>>,
+    token_path: web::Path<String>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    if token_path.as_str() != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let query = query.into_inner();
+    let id = match (query.name, query.uuid, query.prefix) {
+        (Some(name), None, None) => PlayerId::Name(name),
+        (None, Some(uuid), None) => PlayerId::Uuid(uuid),
+        (None, None, Some(prefix)) => {
+            let msg = PlayersSearch {
+                prefix,
+                limit: query.limit.unwrap_or(LOOKUP_LIMIT).min(LOOKUP_LIMIT),
+            };
+            return match players.send(msg).await {
+                Ok(sessions) => HttpResponse::Ok().json(sessions),
+                Err(_) => HttpResponse::InternalServerError().finish(),
+            };
+        }
+        _ => {
+            return HttpResponse::BadRequest().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message: "name、uuid、prefix需填写且只能填写一个".to_string(),
             });
-        } else {
-            return HttpResponse::NotFound().json(ResponseMessage {
+        }
+    };
+    match players.send(PlayerGet { id }).await {
+        Ok(Some(session)) => HttpResponse::Ok().json(session),
+        Ok(None) => HttpResponse::NotFound().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "玩家不在线".to_string(),
+        }),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// 玩家状态查询条件, name和uuid只能填一个
+#[derive(Deserialize)]
+pub struct LastSeenQuery {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub uuid: Option<String>,
+}
+
+// 查询玩家的最后已知状态(是否在线、最后在线时间和所在服务端), 包括离线玩家
+pub async fn player_last_seen(
+    query: web::Query<LastSeenQuery>,
+    players: web::Data<Addr<PlayerManager>>,
+    token_path: web::Path<String>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    if token_path.as_str() != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let id = match query.into_inner() {
+        LastSeenQuery {
+            name: Some(name),
+            uuid: None,
+        } => PlayerId::Name(name),
+        LastSeenQuery {
+            name: None,
+            uuid: Some(uuid),
+        } => PlayerId::Uuid(uuid),
+        _ => {
+            return HttpResponse::BadRequest().json(ResponseMessage {
                 r#type: "error".to_string(),
-                message: "玩家不在线".to_string(),
+                message: "name、uuid需填写且只能填写一个".to_string(),
             });
         }
+    };
+    match players.send(PlayerRecordGet { id }).await {
+        Ok(Some(record)) => HttpResponse::Ok().json(record),
+        Ok(None) => HttpResponse::NotFound().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "玩家不存在".to_string(),
+        }),
+        Err(_) => HttpResponse::InternalServerError().finish(),
     }
-    HttpResponse::NotFound().json(ResponseMessage {
-        r#type: "error".to_string(),
-        message: "玩家不在线".to_string(),
-    })
 }
 
 pub fn player_config(cfg: &mut web::ServiceConfig) {
@@ -137,6 +370,8 @@ pub fn player_config(cfg: &mut web::ServiceConfig) {
             .route("/chat/{token_path}", web::post().to(post_chat))
             .route("/join/{token_path}", web::post().to(player_join))
             .route("/left/{token_path}", web::post().to(player_left))
+            .route("/whisper/{token_path}", web::post().to(post_whisper))
+            .route("/heartbeat/{token_path}", web::post().to(players_heartbeat))
             .route(
                 "/nbt/{player}/upload/{token_path}",
                 web::post().to(player_nbt_upload),
@@ -145,83 +380,262 @@ pub fn player_config(cfg: &mut web::ServiceConfig) {
                 "/nbt/{player}/get/{token_path}",
                 web::get().to(player_nbt_get),
             )
+            .route(
+                "/nbt/{player}/get/{token_path}",
+                web::head().to(player_nbt_get),
+            )
+            .route(
+                "/nbt/{player}/meta/{token_path}",
+                web::get().to(player_nbt_meta),
+            )
+            .route(
+                "/nbt/{player}/inspect/{token_path}",
+                web::get().to(player_nbt_inspect),
+            )
+            .route(
+                "/nbt/{player}/inspect/{section}/{token_path}",
+                web::get().to(player_nbt_inspect_section),
+            )
+            .route(
+                "/nbt/{player}/revisions/{token_path}",
+                web::get().to(revisions_get),
+            )
+            .route(
+                "/nbt/{player}/revisions/{id}/{token_path}",
+                web::get().to(revision_get),
+            )
+            .route(
+                "/nbt/{player}/rollback/{id}/{token_path}",
+                web::post().to(revision_rollback),
+            )
             .route("/get/{token_path}", web::get().to(players_get))
-            .route("/check_online/{player}", web::get().to(player_check_online)),
+            .route(
+                "/check_online/{player}/{token_path}",
+                web::get().to(player_check_online),
+            )
+            .route("/lookup/{token_path}", web::get().to(player_lookup))
+            .route("/last_seen/{token_path}", web::get().to(player_last_seen))
+            .route("/{token_path}", web::get().to(player_sessions_get)),
     );
 }
 
-// 接受玩家nbt文件
+/// 玩家正在转移时拒绝nbt的普通读取, 转移数据只能通过转移接口领取
+async fn check_transfer(transfers: &Addr<TransferManager>, player: &str) -> Option<HttpResponse> {
+    let msg = TransferActive {
+        player: player.to_owned(),
+    };
+    match transfers.send(msg).await {
+        Ok(false) => None,
+        Ok(true) => Some(HttpResponse::Conflict().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "玩家正在转移中".to_string(),
+        })),
+        Err(_) => Some(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+/// 写入玩家nbt的服务端, 用于检查玩家数据租约
+#[derive(Deserialize)]
+pub struct NbtWriter {
+    #[serde(default)]
+    pub server: Option<String>,
+}
+
+// 接受玩家nbt文件, 请求体先写入临时文件, 校验通过后替换玩家当前的nbt
+#[allow(clippy::too_many_arguments)]
 pub async fn player_nbt_upload(
+    req: HttpRequest,
     //路径参数
-    path: web::Path<String>,
+    path: web::Path<(String, String)>,
+    writer: web::Query<NbtWriter>,
     mut binary: web::Payload,
-    token_path: web::Path<String>,
+    transfers: web::Data<Addr<TransferManager>>,
+    revisions: web::Data<NbtRevisionSqlite>,
+    config: web::Data<ServerConfig>,
     token: web::Data<String>,
 ) -> HttpResponse {
-    if token_path.as_str() != token.as_str() {
+    let (player_name, token_path) = path.into_inner();
+    if token_path != token.as_str() {
         return HttpResponse::Unauthorized().json(ResponseMessage {
             r#type: "error".to_string(),
             message: "token错误".to_string(),
         });
     }
-    let player_name = path.as_str();
-
-    let file = format!("{}/{}.nbt", DIR_PATH_PLAYER_NBT, player_name);
+    let player = match StorageKey::player(&player_name) {
+        Ok(player) => player,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message: message.to_string(),
+            })
+        }
+    };
+    let server = writer.into_inner().server;
+    let check = NbtWriteCheck {
+        player: player.as_str().to_owned(),
+        server: server.clone(),
+    };
+    match transfers.send(check).await {
+        Ok(Ok(())) => {}
+        Ok(Err(message)) => {
+            return HttpResponse::Conflict().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message,
+            })
+        }
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
 
-    //创建文件夹
-    let mut file = File::create(file).await.unwrap();
+    let expected = match expected_sha256(&req) {
+        Ok(expected) => expected,
+        Err(response) => return response,
+    };
 
-    //写入文件
-    let mut bytes = web::BytesMut::new();
-    while let Some(item) = binary.next().await {
-        bytes.extend_from_slice(&item.unwrap());
+    let path = player.path();
+    let tmp = upload_tmp_path(&path);
+    let received = match receive_to_file(&req, &mut binary, &tmp, config.max_nbt_size).await {
+        Ok(received) => received,
+        Err(err) => return err.response(),
+    };
+    if let Err(response) = verify(expected.as_deref(), &received) {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return response;
     }
-    match file.write_all(&bytes.to_vec()).await {
-        Ok(_) => HttpResponse::Ok().json(ResponseMessage {
-            r#type: "success".to_string(),
-            message: "文件写入成功".to_string(),
-        }),
-        Err(_err) => {
-            return HttpResponse::InternalServerError().json(ResponseMessage {
+    // 解析nbt, 拒绝不完整或格式错误的数据, 大小已受max_nbt_size限制
+    let meta = match tokio::fs::read(&tmp).await.map(|bytes| nbt::parse(&bytes)) {
+        Ok(Ok(nbt)) => nbt.meta(),
+        Ok(Err(message)) => {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return HttpResponse::BadRequest().json(ResponseMessage {
                 r#type: "error".to_string(),
-                message: "文件写入失败".to_string(),
+                message: message.to_string(),
             });
         }
+        Err(_) => {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    // 替换前从已校验的临时文件保存修订快照, 上传期间的转移和租约变更由提交时的检查拒绝
+    let snapshot = RevisionSnapshot::take(&player, &tmp).await;
+    let commit = NbtCommit {
+        player: player.as_str().to_owned(),
+        server: server.clone(),
+        tmp,
+        path,
+        sha256: received.sha256.clone(),
+        meta: meta.clone(),
+    };
+    match transfers.send(commit).await {
+        Ok(Ok(_)) => {}
+        result => {
+            if let Some(snapshot) = snapshot {
+                snapshot.discard().await;
+            }
+            return match result {
+                Ok(Err(NbtCommitError::Rejected(message))) => {
+                    HttpResponse::Conflict().json(ResponseMessage {
+                        r#type: "error".to_string(),
+                        message,
+                    })
+                }
+                _ => HttpResponse::InternalServerError().json(ResponseMessage {
+                    r#type: "error".to_string(),
+                    message: "文件写入失败".to_string(),
+                }),
+            };
+        }
     }
+    if let Some(snapshot) = snapshot {
+        let server = server.as_deref().unwrap_or_default();
+        let recorded = record_revision(&revisions, &player, snapshot, server, &meta, None);
+        if let Err(message) = recorded.await {
+            log::warn!("玩家 {} 的nbt已写入, 但{}", player.as_str(), message);
+        }
+    }
+    HttpResponse::Ok()
+        .insert_header((ETAG, EntityTag::new_strong(received.sha256.clone())))
+        .insert_header((CONTENT_SHA256_HEADER, received.sha256))
+        .json(ResponseMessage {
+            r#type: "success".to_string(),
+            message: "文件写入成功".to_string(),
+        })
 }
 
-// 提供玩家nbt文件
+// 提供玩家nbt文件, 支持Range请求和If-None-Match条件请求, HEAD请求只返回响应头
 pub async fn player_nbt_get(
+    req: HttpRequest,
     //路径参数
-    path: web::Path<String>,
-    token_path: web::Path<String>,
+    path: web::Path<(String, String)>,
+    transfers: web::Data<Addr<TransferManager>>,
     token: web::Data<String>,
 ) -> HttpResponse {
-    if token_path.as_str() != token.as_str() {
+    let (player_name, token_path) = path.into_inner();
+    if token_path != token.as_str() {
         return HttpResponse::NotFound().json(ResponseMessage {
             r#type: "error".to_string(),
             message: "token错误".to_string(),
         });
     }
-    let player_name = path.as_str();
-    let file = format!("{}/{}.nbt", DIR_PATH_PLAYER_NBT, player_name);
-    let file = File::open(file).await;
-    match file {
-        Ok(file) => {
-            let mut bytes = web::BytesMut::new();
-            let mut reader = tokio::io::BufReader::new(file);
-            let _ = reader.read_buf(&mut bytes).await.unwrap();
-            return HttpResponse::Ok()
-                .content_type("application/octet-stream")
-                .body(bytes.to_vec());
-        }
-        Err(_err) => {
-            return HttpResponse::NotFound().json(ResponseMessage {
+    let player = match StorageKey::player(&player_name) {
+        Ok(player) => player,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(ResponseMessage {
                 r#type: "error".to_string(),
-                message: "文件不存在".to_string(),
-            });
+                message: message.to_string(),
+            })
         }
+    };
+    if let Some(response) = check_transfer(&transfers, player.as_str()).await {
+        return response;
+    }
+    serve_file(&req, &player.path()).await
+}
+
+/// 玩家nbt的文件信息和解析得到的元数据, 文件无法解析时不含format等字段
+#[derive(Serialize)]
+struct PlayerNbtMeta {
+    #[serde(flatten)]
+    file: FileMeta,
+    #[serde(flatten)]
+    nbt: Option<NbtMeta>,
+}
+
+// 玩家nbt的大小、SHA-256、修改时间和格式、压缩方式、数据版本
+pub async fn player_nbt_meta(
+    path: web::Path<(String, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (player_name, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::NotFound().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
     }
+    let player = match StorageKey::player(&player_name) {
+        Ok(player) => player,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message: message.to_string(),
+            })
+        }
+    };
+    let path = player.path();
+    let Ok((mut file, hash)) = open_with_hash(&path).await else {
+        return HttpResponse::NotFound().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "文件不存在".to_string(),
+        });
+    };
+    let nbt = nbt::load_meta(&path, &hash.sha256, &mut file).await;
+    HttpResponse::Ok()
+        .insert_header((ETAG, EntityTag::new_strong(hash.sha256.clone())))
+        .json(PlayerNbtMeta {
+            file: FileMeta::from(hash),
+            nbt,
+        })
 }
 
 #[derive(Serialize, Deserialize)]
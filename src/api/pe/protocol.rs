@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use super::{
+    binary::BinaryKind,
+    player::{PresenceEvent, RosterEntry},
+    transfer::TransferState,
+    whisper::WhisperStatus,
+};
+
+/// JSON帧协议版本, 连接时以`protocol=2`协商
+/// 未协商的会话使用旧协议: 聊天消息以原文下发, 不接收在线状态广播
+pub const FRAMED_PROTOCOL: u32 = 2;
+
+/// 客户端(游戏服)通过WebSocket发送的文本帧
+/// 不是JSON对象的文本按旧协议视为发往默认频道的聊天消息, JSON对象无法解析为该结构时返回错误帧
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// 加入频道
+    Join { channel: String },
+    /// 离开频道
+    Leave { channel: String },
+    /// 发送聊天消息, 未指定频道时发往默认频道
+    /// player为发送消息的玩家, 用于禁言和刷屏检测, 未填写时以服务端名计
+    Chat {
+        #[serde(default)]
+        channel: Option<String>,
+        #[serde(default)]
+        player: Option<String>,
+        msg: String,
+    },
+    /// 请求补发频道内序号大于`since`的消息, 用于重连后同步
+    Replay { channel: String, since: u64 },
+    /// 发送私信, 仅投递到接收玩家所在的服务端
+    Whisper {
+        from: String,
+        to: String,
+        msg: String,
+    },
+    /// 确认收到`deliver`帧, id为该帧的消息id
+    Ack { id: u64 },
+    /// 请求下发二进制数据, 中心服以二进制帧回复
+    Fetch { kind: BinaryKind, key: String },
+    /// 上报本服务端的完整在线玩家列表, 游戏服(重新)连接后发送
+    /// 中心服据此恢复该服务端的玩家, 不在列表中的玩家视为已离开
+    Roster { players: Vec<RosterEntry> },
+    /// 返回`command`帧的执行结果
+    CommandResult {
+        id: u64,
+        #[serde(default = "default_true")]
+        success: bool,
+        #[serde(default)]
+        output: String,
+    },
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// 中心服通过WebSocket下发给游戏服的文本帧
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame<'a> {
+    /// 频道聊天消息, seq为频道内递增序号, 旧协议的会话只收到msg原文
+    Chat {
+        channel: &'a str,
+        seq: u64,
+        msg: &'a str,
+    },
+    /// 补发结束, seq为已补发的最新序号, truncated表示部分消息已超出保留范围
+    ReplayEnd {
+        channel: &'a str,
+        seq: u64,
+        truncated: bool,
+    },
+    /// 私信
+    Whisper {
+        from: &'a str,
+        to: &'a str,
+        msg: &'a str,
+        time: i64,
+    },
+    /// 私信投递结果
+    WhisperResult {
+        from: &'a str,
+        to: &'a str,
+        status: WhisperStatus,
+    },
+    /// 消息被聊天审核拦截
+    Blocked { channel: &'a str, reason: &'a str },
+    /// 需要确认的消息, 仅发给开启消息确认的会话, frame为原始消息帧
+    Deliver { id: u64, frame: &'a RawValue },
+    /// 要求游戏服执行控制台命令, 游戏服执行后回复`command_result`
+    Command { id: u64, command: &'a str },
+    /// 二进制数据上传或下载的处理结果
+    BinaryResult {
+        kind: Option<BinaryKind>,
+        key: &'a str,
+        ok: bool,
+        message: &'a str,
+    },
+    /// 玩家上线、下线或切换服务端, 发给所有使用JSON帧协议的会话
+    /// from为切换前所在的服务端, 仅`switched`事件有
+    Presence {
+        event: PresenceEvent,
+        player: &'a str,
+        uuid: &'a str,
+        server: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        from: Option<&'a str>,
+    },
+    /// 玩家转移状态变化, 发给源服务端和目标服务端
+    /// 目标服务端收到`ready`后领取玩家数据
+    Transfer {
+        id: u64,
+        player: &'a str,
+        from: &'a str,
+        to: &'a str,
+        state: TransferState,
+    },
+    /// 请求处理失败
+    Error { message: &'a str },
+}
+
+impl ServerFrame<'_> {
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
@@ -0,0 +1,258 @@
+use std::path::{Path, PathBuf};
+
+use actix::prelude::*;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use crate::{
+    sql::nbt_revision::{NbtMeta, NbtRevision, NbtRevisionSqlite},
+    DIR_PATH_PLAYER_NBT,
+};
+
+use super::{
+    file_stream::{serve_file, upload_tmp_path},
+    integrity,
+    lease::{NbtCommitError, NbtRestore},
+    player_api::ResponseMessage,
+    storage::StorageKey,
+    transfer::TransferManager,
+};
+
+/// 回滚产生的修订记录的上传服务端
+const ROLLBACK_SERVER: &str = "admin";
+
+/// 玩家的修订目录: {DIR_PATH_PLAYER_NBT}/revisions/{player}
+fn revision_dir(player: &StorageKey) -> PathBuf {
+    Path::new(DIR_PATH_PLAYER_NBT)
+        .join("revisions")
+        .join(player.key())
+}
+
+/// 修订文件路径: {DIR_PATH_PLAYER_NBT}/revisions/{player}/{id}.nbt
+fn revision_path(player: &StorageKey, id: i64) -> PathBuf {
+    revision_dir(player).join(format!("{}.nbt", id))
+}
+
+/// 修订快照, 替换玩家nbt前从即将写入的文件复制
+/// 替换成功后交给`record_revision`保存, 修订内容不受之后其他写入的影响
+pub struct RevisionSnapshot {
+    path: PathBuf,
+}
+
+impl RevisionSnapshot {
+    /// 复制失败时返回None, 此次写入不保存修订
+    pub async fn take(player: &StorageKey, source: &Path) -> Option<RevisionSnapshot> {
+        let dir = revision_dir(player);
+        tokio::fs::create_dir_all(&dir).await.ok();
+        let path = upload_tmp_path(&dir.join("snapshot.nbt"));
+        if let Err(err) = tokio::fs::copy(source, &path).await {
+            log::error!("保存玩家 {} 的nbt修订失败: {}", player.as_str(), err);
+            let _ = tokio::fs::remove_file(&path).await;
+            return None;
+        }
+        Some(RevisionSnapshot { path })
+    }
+
+    /// 玩家nbt未替换时丢弃快照
+    pub async fn discard(self) {
+        let _ = tokio::fs::remove_file(&self.path).await;
+    }
+}
+
+/// 将快照保存为新修订, 并按保留策略清理旧修订
+pub async fn record_revision(
+    store: &NbtRevisionSqlite,
+    player: &StorageKey,
+    snapshot: RevisionSnapshot,
+    server: &str,
+    meta: &NbtMeta,
+    rollback_of: Option<i64>,
+) -> Result<NbtRevision, String> {
+    let size = match tokio::fs::metadata(&snapshot.path).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => {
+            snapshot.discard().await;
+            return Err("文件不存在".to_string());
+        }
+    };
+    let revision = match store.add_revision(player.as_str(), server, size, meta, rollback_of) {
+        Ok(revision) => revision,
+        Err(err) => {
+            log::error!("保存玩家 {} 的nbt修订失败: {}", player.as_str(), err);
+            snapshot.discard().await;
+            return Err("保存修订失败".to_string());
+        }
+    };
+    if let Err(err) = tokio::fs::rename(&snapshot.path, revision_path(player, revision.id)).await {
+        log::error!("保存玩家 {} 的nbt修订失败: {}", player.as_str(), err);
+        snapshot.discard().await;
+        return Err("保存修订失败".to_string());
+    }
+    match store.prune(player.as_str()) {
+        Ok(expired) => {
+            for id in expired {
+                let _ = integrity::remove_file(&revision_path(player, id)).await;
+            }
+        }
+        Err(err) => log::error!("清理玩家 {} 的nbt修订失败: {}", player.as_str(), err),
+    }
+    Ok(revision)
+}
+
+fn token_error() -> HttpResponse {
+    HttpResponse::Unauthorized().json(ResponseMessage {
+        r#type: "error".to_string(),
+        message: "token错误".to_string(),
+    })
+}
+
+fn invalid_player(message: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(ResponseMessage {
+        r#type: "error".to_string(),
+        message: message.to_string(),
+    })
+}
+
+fn rollback_error() -> HttpResponse {
+    HttpResponse::InternalServerError().json(ResponseMessage {
+        r#type: "error".to_string(),
+        message: "回滚失败".to_string(),
+    })
+}
+
+/// 将修订复制到临时文件, 返回复制内容的sha256
+async fn copy_with_hash(source: &Path, tmp: &Path) -> std::io::Result<String> {
+    let (mut file, hash) = integrity::open_with_hash(source).await?;
+    let mut out = tokio::fs::File::create(tmp).await?;
+    tokio::io::copy(&mut file, &mut out).await?;
+    out.sync_all().await?;
+    Ok(hash.sha256)
+}
+
+fn revision_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(ResponseMessage {
+        r#type: "error".to_string(),
+        message: "修订不存在".to_string(),
+    })
+}
+
+// 获取玩家nbt的所有修订, 最新的在前
+pub async fn revisions_get(
+    revisions: web::Data<NbtRevisionSqlite>,
+    path: web::Path<(String, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (player, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return token_error();
+    }
+    let player = match StorageKey::player(&player) {
+        Ok(player) => player,
+        Err(message) => return invalid_player(message),
+    };
+    match revisions.get_revisions(player.as_str()) {
+        Ok(list) => HttpResponse::Ok().json(list),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// 下载玩家nbt的指定修订
+pub async fn revision_get(
+    req: HttpRequest,
+    revisions: web::Data<NbtRevisionSqlite>,
+    path: web::Path<(String, i64, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (player, id, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return token_error();
+    }
+    let player = match StorageKey::player(&player) {
+        Ok(player) => player,
+        Err(message) => return invalid_player(message),
+    };
+    match revisions.get_revision(player.as_str(), id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return revision_not_found(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+    serve_file(&req, &revision_path(&player, id)).await
+}
+
+// 管理员将玩家nbt回滚到指定修订, 回滚结果保存为新修订
+// 玩家正在转移时拒绝, 回滚不检查玩家数据租约
+pub async fn revision_rollback(
+    revisions: web::Data<NbtRevisionSqlite>,
+    transfers: web::Data<Addr<TransferManager>>,
+    path: web::Path<(String, i64, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (player, id, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return token_error();
+    }
+    let player = match StorageKey::player(&player) {
+        Ok(player) => player,
+        Err(message) => return invalid_player(message),
+    };
+    let source_revision = match revisions.get_revision(player.as_str(), id) {
+        Ok(Some(revision)) => revision,
+        Ok(None) => return revision_not_found(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let (source, current) = (revision_path(&player, id), player.path());
+    let Some(snapshot) = RevisionSnapshot::take(&player, &source).await else {
+        return HttpResponse::InternalServerError().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "保存修订失败".to_string(),
+        });
+    };
+    // 先复制到临时文件, 再由转移管理器检查玩家不在转移中后替换
+    let tmp = upload_tmp_path(&current);
+    let sha256 = match copy_with_hash(&source, &tmp).await {
+        Ok(sha256) => sha256,
+        Err(err) => {
+            log::error!("玩家 {} 的nbt回滚失败: {}", player.as_str(), err);
+            let _ = tokio::fs::remove_file(&tmp).await;
+            snapshot.discard().await;
+            return rollback_error();
+        }
+    };
+    let msg = NbtRestore {
+        player: player.as_str().to_owned(),
+        tmp,
+        path: current,
+        sha256,
+        meta: source_revision.meta.clone(),
+    };
+    match transfers.send(msg).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(NbtCommitError::Rejected(message))) => {
+            snapshot.discard().await;
+            return HttpResponse::Conflict().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message,
+            });
+        }
+        Ok(Err(NbtCommitError::Io)) | Err(_) => {
+            snapshot.discard().await;
+            return rollback_error();
+        }
+    }
+    log::warn!("玩家 {} 的nbt已回滚到修订 {}", player.as_str(), id);
+    let meta = &source_revision.meta;
+    let rollback = record_revision(
+        &revisions,
+        &player,
+        snapshot,
+        ROLLBACK_SERVER,
+        meta,
+        Some(id),
+    );
+    match rollback.await {
+        Ok(revision) => HttpResponse::Ok().json(revision),
+        Err(message) => HttpResponse::InternalServerError().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message,
+        }),
+    }
+}
@@ -1,52 +1,257 @@
+use std::{
+    future::Future,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
 use actix::prelude::*;
-use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_http::ws::Item;
+use actix_web::{
+    http::header::{HeaderName, HeaderValue},
+    web::{self, Bytes, BytesMut},
+    Error, HttpRequest, HttpResponse,
+};
 use actix_web_actors::ws;
 use log::info;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
-use crate::api::pe::player::PlayersRemoveByServer;
+use crate::{
+    api::pe::player::PlayersRemoveByServer,
+    config::ServerConfig,
+    sql::{mailbox::MailboxSqlite, nbt_revision::NbtRevisionSqlite},
+};
 
-use super::{chatserver, player::PlayerManager};
+use super::{
+    binary::{self, BinaryKind},
+    chatserver,
+    command::CommandResponse,
+    delivery::SendQueue,
+    file_stream::{to_hex, upload_tmp_path},
+    integrity::commit_file,
+    lease::{NbtCommit, NbtCommitError, NbtWriteCheck},
+    moderation::WHISPER_CHANNEL,
+    nbt,
+    player::{PlayerManager, PlayersSync},
+    player_api::ResponseMessage,
+    protocol::{ClientFrame, ServerFrame, FRAMED_PROTOCOL},
+    revision::{record_revision, RevisionSnapshot},
+    transfer::{TransferActive, TransferManager},
+    whisper::{deliver_whisper, Whisper},
+};
 
 pub fn chatserver_config(cfg: &mut web::ServiceConfig) {
-    cfg.service(web::resource("/ws").route(web::get().to(ws_route)));
+    cfg.service(web::resource("/ws").route(web::get().to(ws_route)))
+        .route("/sessions/{token_path}", web::get().to(sessions_get));
+}
+
+// 获取所有已连接的会话
+pub async fn sessions_get(
+    srv: web::Data<Addr<chatserver::ChatServer>>,
+    token_path: web::Path<String>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    if token_path.as_str() != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    match srv.send(chatserver::SessionsGet).await {
+        Ok(sessions) => HttpResponse::Ok().json(sessions),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
 }
 
+/// 连接参数
+/// server_name: 服务端名
+/// token: 该服务端的独立token, 未配置独立token时为全局token
+/// takeover: 同名服务端已连接时接管旧连接, 否则拒绝新连接
+/// protocol: 协议版本, 为2时下发JSON帧, 不填或为1时按旧协议下发聊天消息原文
+/// ack: 开启消息确认, 下发的消息包装为`deliver`帧, 客户端需回复`ack`, 需要protocol为2
+/// compress: 压缩方式, 目前仅支持`deflate`, 开启后超过阈值的文本帧以压缩文本二进制帧发送
+/// channels: 连接时加入的频道, 逗号分隔, 默认频道总是加入
 #[derive(Deserialize, Debug)]
 pub struct ServerName {
     server_name: String,
+    #[serde(default)]
+    token: String,
+    #[serde(default)]
+    takeover: bool,
+    #[serde(default)]
+    protocol: Option<u32>,
+    #[serde(default)]
+    ack: bool,
+    #[serde(default)]
+    compress: Option<String>,
+    #[serde(default)]
+    channels: Option<String>,
 }
+#[allow(clippy::too_many_arguments)]
 pub async fn ws_route(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<chatserver::ChatServer>>,
     players: web::Data<Addr<PlayerManager>>,
+    transfers: web::Data<Addr<TransferManager>>,
+    config: web::Data<ServerConfig>,
+    mailbox: web::Data<MailboxSqlite>,
+    revisions: web::Data<NbtRevisionSqlite>,
     server_name: web::Query<ServerName>,
 ) -> Result<HttpResponse, Error> {
     let name = &server_name.server_name;
     if name.is_empty() {
         return Ok(HttpResponse::BadRequest().finish());
     }
-    ws::start(
+    if !config.check_server_token(name, &server_name.token) {
+        log::warn!("{} 连接认证失败", name);
+        return ws::start(
+            RejectedSession {
+                reason: "token错误".to_string(),
+            },
+            &req,
+            stream,
+        );
+    }
+    let protocol = match server_name.protocol {
+        None | Some(1) => 1,
+        Some(FRAMED_PROTOCOL) => FRAMED_PROTOCOL,
+        Some(_) => return Ok(HttpResponse::BadRequest().finish()),
+    };
+    if server_name.ack && protocol != FRAMED_PROTOCOL {
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+    let compress = match server_name.compress.as_deref() {
+        None | Some("") | Some("none") => false,
+        Some("deflate") => true,
+        Some(_) => return Ok(HttpResponse::BadRequest().finish()),
+    };
+    let channels = server_name
+        .channels
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|channel| !channel.is_empty())
+        .map(str::to_owned)
+        .collect();
+    ws::WsResponseBuilder::new(
         WsSession {
             id: 0,
+            registered: false,
             name: name.to_string(),
+            takeover: server_name.takeover,
+            protocol,
+            ack: server_name.ack,
+            compress_threshold: compress.then_some(config.ws_compress_threshold),
+            remote_addr: req.peer_addr().map(|addr| addr.to_string()),
+            stats: Arc::default(),
+            queue: Arc::default(),
+            channels,
+            hb: Instant::now(),
+            heartbeat_interval: Duration::from_secs(config.heartbeat_interval),
+            client_timeout: Duration::from_secs(config.client_timeout),
+            disconnect_reason: None,
+            max_frame_size: config.ws_max_frame_size,
+            max_nbt_size: config.max_nbt_size,
+            fragment: None,
             addr: srv.get_ref().clone(),
             playermanager: players.get_ref().clone(),
+            transfers: transfers.get_ref().clone(),
+            mailbox: mailbox.get_ref().clone(),
+            revisions: revisions.get_ref().clone(),
         },
         &req,
         stream,
     )
+    .frame_size(config.ws_max_frame_size)
+    .start()
+    .map(|mut res| {
+        if compress {
+            res.headers_mut().insert(
+                HeaderName::from_static("x-ws-compression"),
+                HeaderValue::from_static("deflate"),
+            );
+        }
+        res
+    })
+}
+
+/// 未接收完的分片消息
+#[derive(Debug)]
+pub struct Fragment {
+    /// 是否为二进制消息
+    binary: bool,
+    buf: BytesMut,
+}
+
+/// 认证失败的连接, 握手完成后立即以策略违规关闭
+struct RejectedSession {
+    reason: String,
+}
+
+impl Actor for RejectedSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(self.reason.clone()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for RejectedSession {
+    fn handle(&mut self, _: Result<ws::Message, ws::ProtocolError>, _: &mut Self::Context) {}
 }
 
 #[derive(Debug)]
 pub struct WsSession {
     pub id: usize,
- 
+    /// 是否已在聊天服务器注册, 未注册或被接管的会话断开时不做清理
+    pub registered: bool,
+
     pub name: String,
+    /// 接管同名服务端的旧连接
+    pub takeover: bool,
+    /// 协议版本
+    pub protocol: u32,
+    /// 开启消息确认
+    pub ack: bool,
+    /// 压缩阈值, 未开启压缩时为None
+    pub compress_threshold: Option<usize>,
+    /// 客户端地址
+    pub remote_addr: Option<String>,
+    /// 消息计数
+    pub stats: Arc<chatserver::SessionStats>,
+    /// 待发送的消息, 由聊天服务器写入
+    pub queue: Arc<SendQueue>,
+    /// 连接时加入的频道
+    pub channels: Vec<String>,
+    /// 最后一次收到客户端帧的时间
+    pub hb: Instant,
+    /// 心跳间隔
+    pub heartbeat_interval: Duration,
+    /// 客户端超时时间
+    pub client_timeout: Duration,
+    /// 断开原因, 断开时输出到日志
+    pub disconnect_reason: Option<String>,
+    /// 单条消息的最大字节数
+    pub max_frame_size: usize,
+    /// 玩家nbt的最大字节数
+    pub max_nbt_size: u64,
+    /// 未接收完的分片消息
+    pub fragment: Option<Fragment>,
     /// Chat server
     pub addr: Addr<chatserver::ChatServer>,
     pub playermanager: Addr<PlayerManager>,
+    /// 玩家转移, 转移期间拒绝读写玩家nbt
+    pub transfers: Addr<TransferManager>,
+    /// 离线信箱
+    pub mailbox: MailboxSqlite,
+    /// 玩家nbt修订记录
+    pub revisions: NbtRevisionSqlite,
 }
 
 impl Actor for WsSession {
@@ -54,40 +259,495 @@ impl Actor for WsSession {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("{} 已连接", self.name);
+        self.heartbeat(ctx);
         // across all routes within application
         let addr = ctx.address();
         self.addr
             .send(chatserver::Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                kick: addr.recipient(),
+                name: self.name.clone(),
+                takeover: self.takeover,
+                protocol: self.protocol,
+                ack: self.ack,
+                channels: self.channels.clone(),
+                remote_addr: self.remote_addr.clone(),
+                stats: self.stats.clone(),
+                queue: self.queue.clone(),
             })
             .into_actor(self)
-            .then(|res: Result<usize, MailboxError>, act: &mut WsSession, ctx: &mut ws::WebsocketContext<WsSession>| {
-                match res {
-                    Ok(res) => act.id = res,
-                    // something is wrong with chat server
-                    _ => ctx.stop(),
-                }
-                fut::ready(())
-            })
+            .then(
+                |res: Result<Option<usize>, MailboxError>,
+                 act: &mut WsSession,
+                 ctx: &mut ws::WebsocketContext<WsSession>| {
+                    match res {
+                        Ok(Some(res)) => {
+                            act.id = res;
+                            act.registered = true;
+                        }
+                        Ok(None) => {
+                            log::warn!("{} 已在其他连接登录, 拒绝新连接", act.name);
+                            ctx.close(Some(ws::CloseReason {
+                                code: ws::CloseCode::Policy,
+                                description: Some("服务端名已被占用".to_string()),
+                            }));
+                            ctx.stop();
+                        }
+                        // something is wrong with chat server
+                        _ => {
+                            act.disconnect_reason = Some("聊天服务器不可用".to_string());
+                            ctx.stop()
+                        }
+                    }
+                    fut::ready(())
+                },
+            )
             .wait(ctx);
     }
 
     fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
+        if !self.registered {
+            return Running::Stop;
+        }
+        self.addr.do_send(chatserver::Disconnect { id: self.id });
         let server_name = &self.name;
         self.playermanager.do_send(PlayersRemoveByServer {
             server: server_name.to_string(),
         });
-        info!("{} 已断开", server_name);
+        info!(
+            "{} 已断开: {}",
+            server_name,
+            self.disconnect_reason.as_deref().unwrap_or("连接关闭")
+        );
         Running::Stop
     }
 }
 
-/// Handle messages from chat server, we simply send it to peer websocket
-impl Handler<chatserver::Message> for WsSession {
+/// 聊天服务器要求断开连接
+/// 同名服务端接管时玩家列表由新连接继续维护, 因此不清理该服务端的玩家
+impl Handler<chatserver::Kick> for WsSession {
     type Result = ();
 
-    fn handle(&mut self, msg: chatserver::Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+    fn handle(&mut self, msg: chatserver::Kick, ctx: &mut Self::Context) {
+        if msg.replaced {
+            self.registered = false;
+            info!("{} 已断开: {}", self.name, msg.reason);
+        } else {
+            self.disconnect_reason = Some(msg.reason.clone());
+        }
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
+    }
+}
+
+impl WsSession {
+    /// 合并分片消息, 收到最后一个分片后按文本或二进制消息处理
+    fn handle_continuation(&mut self, item: Item, ctx: &mut ws::WebsocketContext<Self>) {
+        let first = match &item {
+            Item::FirstText(_) => Some(false),
+            Item::FirstBinary(_) => Some(true),
+            _ => None,
+        };
+        if let Some(binary) = first {
+            if self.fragment.is_some() {
+                self.disconnect_reason = Some("上一条分片消息未结束".to_string());
+                ctx.stop();
+                return;
+            }
+            self.fragment = Some(Fragment {
+                binary,
+                buf: BytesMut::new(),
+            });
+        }
+        let (bytes, last) = match item {
+            Item::FirstText(bytes) | Item::FirstBinary(bytes) => (bytes, false),
+            Item::Continue(bytes) => (bytes, false),
+            Item::Last(bytes) => (bytes, true),
+        };
+        let Some(fragment) = self.fragment.as_mut() else {
+            self.disconnect_reason = Some("收到未开始的分片消息".to_string());
+            ctx.stop();
+            return;
+        };
+        if fragment.buf.len() + bytes.len() > self.max_frame_size {
+            self.disconnect_reason = Some("分片消息超过大小限制".to_string());
+            ctx.stop();
+            return;
+        }
+        fragment.buf.extend_from_slice(&bytes);
+        if !last {
+            return;
+        }
+        let Some(fragment) = self.fragment.take() else {
+            return;
+        };
+        if fragment.binary {
+            self.handle_binary(fragment.buf.freeze(), ctx);
+        } else {
+            match std::str::from_utf8(&fragment.buf) {
+                Ok(text) => self.handle_text(text.trim(), ctx),
+                Err(_) => {
+                    self.disconnect_reason = Some("分片文本消息不是UTF-8".to_string());
+                    ctx.stop();
+                }
+            }
+        }
+    }
+
+    /// 处理客户端二进制帧, 按帧头保存玩家nbt或世界区块
+    fn handle_binary(&mut self, bytes: Bytes, ctx: &mut ws::WebsocketContext<Self>) {
+        let frame = match binary::decode(&bytes) {
+            Ok(frame) => frame,
+            Err(message) => {
+                ctx.text(
+                    ServerFrame::BinaryResult {
+                        kind: None,
+                        key: "",
+                        ok: false,
+                        message,
+                    }
+                    .to_text(),
+                );
+                return;
+            }
+        };
+        if frame.kind == BinaryKind::CompressedText {
+            match binary::decompress_text(frame.payload, self.max_frame_size) {
+                Ok(text) => self.handle_text(text.trim(), ctx),
+                Err(message) => ctx.text(ServerFrame::Error { message }.to_text()),
+            }
+            return;
+        }
+        let (kind, key) = (frame.kind, frame.key.to_owned());
+        let storage = match kind.storage_key(&key) {
+            Ok(storage) => storage,
+            Err(message) => {
+                ctx.text(
+                    ServerFrame::BinaryResult {
+                        kind: Some(kind),
+                        key: &key,
+                        ok: false,
+                        message,
+                    }
+                    .to_text(),
+                );
+                return;
+            }
+        };
+        let path = storage.path();
+        let payload = bytes.slice(bytes.len() - frame.payload.len()..);
+        if kind == BinaryKind::PlayerNbt && payload.len() as u64 > self.max_nbt_size {
+            let message = format!("文件超过大小限制({}字节)", self.max_nbt_size);
+            ctx.text(
+                ServerFrame::BinaryResult {
+                    kind: Some(kind),
+                    key: &key,
+                    ok: false,
+                    message: &message,
+                }
+                .to_text(),
+            );
+            return;
+        }
+        info!("{} 上传 {:?}: {}", self.name, kind, key);
+        let check = (kind == BinaryKind::PlayerNbt).then(|| {
+            self.transfers.send(NbtWriteCheck {
+                player: storage.as_str().to_owned(),
+                server: Some(self.name.clone()),
+            })
+        });
+        let meta = match kind {
+            BinaryKind::PlayerNbt => match nbt::parse(&payload) {
+                Ok(nbt) => Some(nbt.meta()),
+                Err(message) => {
+                    ctx.text(
+                        ServerFrame::BinaryResult {
+                            kind: Some(kind),
+                            key: &key,
+                            ok: false,
+                            message,
+                        }
+                        .to_text(),
+                    );
+                    return;
+                }
+            },
+            _ => None,
+        };
+        let (transfers, revisions, server) = (
+            self.transfers.clone(),
+            self.revisions.clone(),
+            self.name.clone(),
+        );
+        async move {
+            if let Some(check) = check {
+                check
+                    .await
+                    .unwrap_or_else(|_| Err("玩家数据检查失败".to_string()))?;
+            }
+            // 先写入临时文件, 与HTTP上传相同地替换目标文件并保存校验和
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await.ok();
+            }
+            let tmp = upload_tmp_path(&path);
+            let sha256 = to_hex(&Sha256::digest(&payload));
+            if tokio::fs::write(&tmp, &payload).await.is_err() {
+                let _ = tokio::fs::remove_file(&tmp).await;
+                return Err("文件写入失败".to_string());
+            }
+            let Some(meta) = meta else {
+                // 世界区块不受租约和转移限制, 直接替换
+                if commit_file(&tmp, &path, &sha256).await.is_err() {
+                    let _ = tokio::fs::remove_file(&tmp).await;
+                    return Err("文件写入失败".to_string());
+                }
+                return Ok(());
+            };
+            let snapshot = RevisionSnapshot::take(&storage, &tmp).await;
+            let commit = NbtCommit {
+                player: storage.as_str().to_owned(),
+                server: Some(server.clone()),
+                tmp,
+                path,
+                sha256,
+                meta: meta.clone(),
+            };
+            match transfers.send(commit).await {
+                Ok(Ok(_)) => {}
+                result => {
+                    if let Some(snapshot) = snapshot {
+                        snapshot.discard().await;
+                    }
+                    return Err(match result {
+                        Ok(Err(NbtCommitError::Rejected(message))) => message,
+                        _ => "文件写入失败".to_string(),
+                    });
+                }
+            }
+            if let Some(snapshot) = snapshot {
+                let recorded =
+                    record_revision(&revisions, &storage, snapshot, &server, &meta, None);
+                if let Err(message) = recorded.await {
+                    log::warn!("玩家 {} 的nbt已写入, 但{}", storage.as_str(), message);
+                }
+            }
+            Ok(())
+        }
+        .into_actor(self)
+        .map(move |res, _act, ctx| {
+            let (ok, message) = match res {
+                Ok(_) => (true, "文件写入成功".to_string()),
+                Err(message) => (false, message),
+            };
+            ctx.text(
+                ServerFrame::BinaryResult {
+                    kind: Some(kind),
+                    key: &key,
+                    ok,
+                    message: &message,
+                }
+                .to_text(),
+            );
+        })
+        .spawn(ctx);
+    }
+
+    /// 读取玩家nbt或世界区块并以二进制帧发送给客户端
+    fn fetch_binary(
+        &mut self,
+        kind: BinaryKind,
+        key: String,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let storage = match kind.storage_key(&key) {
+            Ok(storage) => storage,
+            Err(message) => {
+                ctx.text(
+                    ServerFrame::BinaryResult {
+                        kind: Some(kind),
+                        key: &key,
+                        ok: false,
+                        message,
+                    }
+                    .to_text(),
+                );
+                return;
+            }
+        };
+        let transferring = self.transferring(kind, storage.as_str());
+        async move {
+            if transferring.await {
+                return Err("玩家正在转移中");
+            }
+            tokio::fs::read(storage.path())
+                .await
+                .map_err(|_| "文件不存在")
+        }
+        .into_actor(self)
+        .map(move |res, _act, ctx| match res {
+            Ok(data) => ctx.binary(binary::encode(kind, &key, &data)),
+            Err(message) => ctx.text(
+                ServerFrame::BinaryResult {
+                    kind: Some(kind),
+                    key: &key,
+                    ok: false,
+                    message,
+                }
+                .to_text(),
+            ),
+        })
+        .spawn(ctx);
+    }
+
+    /// 查询玩家nbt是否因玩家转移而被锁定, 其他数据类型不受影响
+    fn transferring(&self, kind: BinaryKind, key: &str) -> impl Future<Output = bool> {
+        let check = (kind == BinaryKind::PlayerNbt).then(|| {
+            self.transfers.send(TransferActive {
+                player: key.to_owned(),
+            })
+        });
+        async move {
+            match check {
+                Some(check) => check.await.unwrap_or(true),
+                None => false,
+            }
+        }
+    }
+
+    /// 定时向客户端发送ping, 超时未收到任何帧时断开连接
+    fn heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.run_interval(self.heartbeat_interval, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > act.client_timeout {
+                log::warn!("{} 心跳超时", act.name);
+                act.disconnect_reason = Some("心跳超时".to_string());
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+
+    /// 处理客户端文本帧
+    fn handle_text(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        // 只有不是JSON对象的文本才按旧协议视为聊天消息, 避免格式错误或未知类型的帧被当作聊天广播
+        let frame = match serde_json::from_str::<serde_json::Value>(text) {
+            Ok(value @ serde_json::Value::Object(_)) => match ClientFrame::deserialize(value) {
+                Ok(frame) => frame,
+                Err(err) => {
+                    let message = format!("无法解析的消息: {}", err);
+                    ctx.text(ServerFrame::Error { message: &message }.to_text());
+                    return;
+                }
+            },
+            _ => ClientFrame::Chat {
+                channel: None,
+                player: None,
+                msg: text.to_owned(),
+            },
+        };
+        match frame {
+            ClientFrame::Join { channel } => {
+                self.addr
+                    .send(chatserver::Join {
+                        id: self.id,
+                        channel: channel.clone(),
+                    })
+                    .into_actor(self)
+                    .then(move |res, _act, ctx: &mut ws::WebsocketContext<Self>| {
+                        if !matches!(res, Ok(true)) {
+                            let message = format!("加入频道失败: {}", channel);
+                            ctx.text(ServerFrame::Error { message: &message }.to_text());
+                        }
+                        fut::ready(())
+                    })
+                    .spawn(ctx);
+            }
+            ClientFrame::Leave { channel } => self.addr.do_send(chatserver::Leave {
+                id: self.id,
+                channel,
+            }),
+            ClientFrame::Chat {
+                channel,
+                player,
+                msg,
+            } => self.addr.do_send(chatserver::ClientMessage {
+                id: self.id,
+                channel: channel.unwrap_or_else(|| chatserver::DEFAULT_CHANNEL.to_owned()),
+                player,
+                msg,
+            }),
+            ClientFrame::Replay { channel, since } => self.addr.do_send(chatserver::Replay {
+                id: self.id,
+                channel,
+                since,
+            }),
+            ClientFrame::CommandResult {
+                id,
+                success,
+                output,
+            } => self.addr.do_send(CommandResponse {
+                server: self.name.clone(),
+                id,
+                success,
+                output,
+            }),
+            ClientFrame::Fetch { kind, key } => self.fetch_binary(kind, key, ctx),
+            ClientFrame::Roster { players } => self.playermanager.do_send(PlayersSync {
+                server: self.name.clone(),
+                players,
+            }),
+            ClientFrame::Ack { id } => self.addr.do_send(chatserver::Ack {
+                id: self.id,
+                msg_id: id,
+            }),
+            ClientFrame::Whisper { from, to, msg } => {
+                let whisper = Whisper { from, to, msg };
+                let players = self.playermanager.clone();
+                let srv = self.addr.clone();
+                let mailbox = self.mailbox.clone();
+                async move {
+                    let status = deliver_whisper(&whisper, &players, &srv, &mailbox).await;
+                    (whisper, status)
+                }
+                .into_actor(self)
+                .map(|(whisper, status), _act, ctx| {
+                    let text = match status {
+                        Ok(status) => ServerFrame::WhisperResult {
+                            from: &whisper.from,
+                            to: &whisper.to,
+                            status,
+                        }
+                        .to_text(),
+                        Err(reason) => ServerFrame::Blocked {
+                            channel: WHISPER_CHANNEL,
+                            reason: &reason,
+                        }
+                        .to_text(),
+                    };
+                    ctx.text(text);
+                })
+                .spawn(ctx);
+            }
+        }
+    }
+}
+
+/// Handle messages from chat server, we simply send them to peer websocket
+impl Handler<chatserver::Flush> for WsSession {
+    type Result = ();
+
+    fn handle(&mut self, _: chatserver::Flush, ctx: &mut Self::Context) {
+        for text in self.queue.take() {
+            match self.compress_threshold {
+                Some(threshold) if text.len() > threshold => {
+                    ctx.binary(binary::compress_text(&text))
+                }
+                _ => ctx.text(text),
+            }
+        }
     }
 }
 
@@ -95,30 +755,43 @@ impl Handler<chatserver::Message> for WsSession {
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         let msg = match msg {
-            Err(_) => {
+            Err(err) => {
+                self.disconnect_reason = Some(format!("协议错误: {}", err));
                 ctx.stop();
                 return;
             }
             Ok(msg) => msg,
         };
         log::debug!("WEBSOCKET MESSAGE: {msg:?}");
+        self.hb = Instant::now();
+        self.stats.received.fetch_add(1, Ordering::Relaxed);
         match msg {
-            ws::Message::Ping(_msg) => {}
+            ws::Message::Ping(msg) => ctx.pong(&msg),
             ws::Message::Pong(_) => {}
-            ws::Message::Text(text) => {
-                let msg: String = text.trim().to_owned();
-                self.addr
-                    .do_send(chatserver::ClientMessage { id: self.id, msg })
-            }
-            ws::Message::Binary(_) => println!("Unexpected binary"),
+            ws::Message::Text(text) => self.handle_text(text.trim(), ctx),
+            ws::Message::Binary(bytes) => self.handle_binary(bytes, ctx),
             ws::Message::Close(reason) => {
+                self.disconnect_reason = Some(match &reason {
+                    Some(reason) => format!(
+                        "客户端关闭连接({:?}) {}",
+                        reason.code,
+                        reason.description.as_deref().unwrap_or_default()
+                    ),
+                    None => "客户端关闭连接".to_string(),
+                });
                 ctx.close(reason);
                 ctx.stop();
             }
-            ws::Message::Continuation(_) => {
-                ctx.stop();
-            }
+            ws::Message::Continuation(item) => self.handle_continuation(item, ctx),
             ws::Message::Nop => (),
         }
     }
+
+    /// 连接未发送关闭帧即中断
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        if self.disconnect_reason.is_none() {
+            self.disconnect_reason = Some("连接中断".to_string());
+        }
+        ctx.stop();
+    }
 }
@@ -0,0 +1,484 @@
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{sql::nbt_revision::NbtRevisionSqlite, DIR_PATH_PLAYER_NBT, DIR_PATH_WORLD};
+
+use super::{integrity::hash_path, nbt::meta_path, player::normalize_uuid};
+
+/// 玩家名最大长度, Java版为16, 基岩版玩家名带有后缀和前缀时会更长
+const MAX_PLAYER_NAME_LEN: usize = 32;
+const MAX_WORLD_NAME_LEN: usize = 64;
+const MAX_CHUNK_NAME_LEN: usize = 128;
+
+/// Windows保留的设备名, 不论扩展名都无法作为文件名
+const RESERVED_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// 存储对象类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    /// 玩家nbt: {DIR_PATH_PLAYER_NBT}/{key}.nbt
+    PlayerNbt,
+    /// 世界存档: {DIR_PATH_WORLD}/{key}.zip
+    World,
+    /// 世界区块: {DIR_PATH_WORLD}/chunks/{key}.bin
+    WorldChunk,
+}
+
+/// 经过校验的存储键, 只能通过校验函数创建, 保证拼接出的路径不会离开存储目录
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageKey {
+    kind: StorageKind,
+    /// 规范化后的标识
+    name: String,
+    /// 文件名
+    key: String,
+}
+
+impl StorageKey {
+    /// 玩家标识: uuid统一为小写8-4-4-4-12格式, 玩家名保留大小写
+    /// 玩家名允许字母、数字、`_`、`-`、`.`和空格, 除字母、数字、`_`、`-`外的字符按%XX编码, 保证不同玩家名的键不会冲突
+    /// 与系统保留名相同的玩家名编码首字符
+    pub fn player(id: &str) -> Result<StorageKey, &'static str> {
+        if is_uuid(id) {
+            let uuid = normalize_uuid(id);
+            return Ok(StorageKey {
+                kind: StorageKind::PlayerNbt,
+                name: uuid.clone(),
+                key: uuid,
+            });
+        }
+        check_common(id, MAX_PLAYER_NAME_LEN)?;
+        if id.starts_with(' ') || id.ends_with(' ') {
+            return Err("玩家名首尾不能有空格");
+        }
+        if !id.chars().all(|c| is_plain(c) || c == '.' || c == ' ') {
+            return Err("玩家名包含非法字符");
+        }
+        // 系统保留名是合法的玩家名, 编码首字符避免与设备名相同
+        let reserved = check_reserved(id).is_err();
+        let mut key = String::with_capacity(id.len());
+        for (i, c) in id.chars().enumerate() {
+            if is_plain(c) && !(reserved && i == 0) {
+                key.push(c);
+            } else {
+                let _ = write!(key, "%{:02X}", c as u32);
+            }
+        }
+        Ok(StorageKey {
+            kind: StorageKind::PlayerNbt,
+            name: id.to_owned(),
+            key,
+        })
+    }
+
+    /// 世界名: 只允许字母、数字、`_`、`-`
+    pub fn world(name: &str) -> Result<StorageKey, &'static str> {
+        check_common(name, MAX_WORLD_NAME_LEN)?;
+        if !name.chars().all(is_plain) {
+            return Err("世界名包含非法字符");
+        }
+        check_reserved(name)?;
+        Ok(StorageKey {
+            kind: StorageKind::World,
+            name: name.to_owned(),
+            key: name.to_owned(),
+        })
+    }
+
+    /// 区块名: 允许字母、数字、`_`、`-`、`.`, 不能以`.`开头
+    pub fn chunk(name: &str) -> Result<StorageKey, &'static str> {
+        check_common(name, MAX_CHUNK_NAME_LEN)?;
+        if name.starts_with('.') {
+            return Err("区块名不能以.开头");
+        }
+        if !name.chars().all(|c| is_plain(c) || c == '.') {
+            return Err("区块名包含非法字符");
+        }
+        check_reserved(name)?;
+        Ok(StorageKey {
+            kind: StorageKind::WorldChunk,
+            name: name.to_owned(),
+            key: name.to_owned(),
+        })
+    }
+
+    /// 规范化后的标识, 用作租约、转移和修订记录中的玩家标识, 再次校验结果不变
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    /// 文件名(不含扩展名)
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// 文件路径
+    pub fn path(&self) -> PathBuf {
+        let path = match self.kind {
+            StorageKind::PlayerNbt => format!("{}/{}.nbt", DIR_PATH_PLAYER_NBT, self.key),
+            StorageKind::World => format!("{}/{}.zip", DIR_PATH_WORLD, self.key),
+            StorageKind::WorldChunk => format!("{}/chunks/{}.bin", DIR_PATH_WORLD, self.key),
+        };
+        PathBuf::from(path)
+    }
+}
+
+fn is_plain(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/// 8-4-4-4-12格式或32位十六进制的uuid
+fn is_uuid(id: &str) -> bool {
+    let hex = |s: &str| s.chars().all(|c| c.is_ascii_hexdigit());
+    match id.len() {
+        32 => hex(id),
+        36 => {
+            let parts: Vec<&str> = id.split('-').collect();
+            parts.iter().map(|part| part.len()).eq([8, 4, 4, 4, 12]) && parts.into_iter().all(hex)
+        }
+        _ => false,
+    }
+}
+
+/// 所有标识共同的检查: 长度、路径分隔符和路径穿越
+fn check_common(name: &str, max_len: usize) -> Result<(), &'static str> {
+    if name.is_empty() {
+        return Err("名称为空");
+    }
+    if name.contains(['/', '\\']) || name.chars().all(|c| c == '.') || name.contains("..") {
+        return Err("名称包含路径");
+    }
+    if name.len() > max_len {
+        return Err("名称过长");
+    }
+    Ok(())
+}
+
+/// 拒绝Windows保留的设备名, 以第一个`.`前的部分判断
+fn check_reserved(name: &str) -> Result<(), &'static str> {
+    let base = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| base.eq_ignore_ascii_case(reserved))
+    {
+        return Err("名称为系统保留名");
+    }
+    Ok(())
+}
+
+/// 启动时迁移旧版本按原始玩家名保存的文件, 已迁移的文件不受影响
+/// 玩家nbt及其校验和、修订目录改为当前的存储键, 修订记录中未规范化的uuid改为规范格式
+/// 不符合当前规则的世界存档无法迁移, 只输出警告
+pub async fn migrate_legacy_files(revisions: &NbtRevisionSqlite) {
+    for (from, to) in migrate_player_dir(Path::new(DIR_PATH_PLAYER_NBT)).await {
+        if let Err(err) = revisions.rename_player(&from, &to) {
+            log::error!("迁移玩家 {} 的nbt修订记录失败: {}", from, err);
+        }
+    }
+    for file in list_dir(Path::new(DIR_PATH_WORLD), false).await {
+        let Some(name) = file.strip_suffix(".zip") else {
+            continue;
+        };
+        if StorageKey::world(name).is_err() {
+            log::warn!("世界存档 {} 的名称不符合当前规则, 需要手动重命名", file);
+        }
+    }
+}
+
+/// 按旧版本原始名称保存、存储键已变化的玩家, 已是存储键或名称非法时返回None
+fn legacy_player(name: &str) -> Option<StorageKey> {
+    StorageKey::player(name)
+        .ok()
+        .filter(|player| player.key() != name)
+}
+
+/// 迁移玩家目录中的nbt文件和修订目录, 返回标识发生变化的玩家(旧标识, 新标识)
+/// 目标文件已存在时保留旧文件并输出警告
+async fn migrate_player_dir(dir: &Path) -> Vec<(String, String)> {
+    let mut renamed = Vec::new();
+    for file in list_dir(dir, false).await {
+        let Some((name, player)) = file
+            .strip_suffix(".nbt")
+            .and_then(|name| Some((name, legacy_player(name)?)))
+        else {
+            continue;
+        };
+        if player.as_str() != name {
+            renamed.push((name.to_owned(), player.as_str().to_owned()));
+        }
+        let from = dir.join(&file);
+        let to = dir.join(format!("{}.nbt", player.key()));
+        if tokio::fs::try_exists(&to).await.unwrap_or(true) {
+            log::warn!("玩家 {} 的nbt与 {} 冲突, 未迁移", name, to.display());
+            continue;
+        }
+        if let Err(err) = tokio::fs::rename(&from, &to).await {
+            log::error!("迁移玩家 {} 的nbt失败: {}", name, err);
+            continue;
+        }
+        let _ = tokio::fs::rename(hash_path(&from), hash_path(&to)).await;
+        let _ = tokio::fs::rename(meta_path(&from), meta_path(&to)).await;
+        log::info!("玩家 {} 的nbt已迁移到 {}", name, to.display());
+    }
+
+    // 修订id全局唯一, 目标目录已存在时逐个移入
+    let revisions = dir.join("revisions");
+    for name in list_dir(&revisions, true).await {
+        let Some(player) = legacy_player(&name) else {
+            continue;
+        };
+        if player.as_str() != name {
+            renamed.push((name.clone(), player.as_str().to_owned()));
+        }
+        let (from, to) = (revisions.join(&name), revisions.join(player.key()));
+        tokio::fs::create_dir_all(&to).await.ok();
+        let mut moved = true;
+        for file in list_dir(&from, false).await {
+            let target = to.join(&file);
+            if tokio::fs::try_exists(&target).await.unwrap_or(true)
+                || tokio::fs::rename(from.join(&file), target).await.is_err()
+            {
+                log::warn!("玩家 {} 的修订文件 {} 未迁移", name, file);
+                moved = false;
+            }
+        }
+        if moved {
+            let _ = tokio::fs::remove_dir(&from).await;
+        }
+    }
+    renamed.sort();
+    renamed.dedup();
+    renamed
+}
+
+/// 目录中的文件名, dirs为true时列出子目录名
+async fn list_dir(dir: &Path, dirs: bool) -> Vec<String> {
+    let mut names = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return names;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let is_dir = match entry.file_type().await {
+            Ok(file_type) => file_type.is_dir(),
+            Err(_) => continue,
+        };
+        if is_dir == dirs {
+            if let Ok(name) = entry.file_name().into_string() {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn player_names_map_to_keys() {
+        assert_eq!(StorageKey::player("Steve").unwrap().key(), "Steve");
+        assert_eq!(StorageKey::player("Alex_01-x").unwrap().key(), "Alex_01-x");
+        assert_eq!(StorageKey::player("A B").unwrap().key(), "A%20B");
+        assert_eq!(
+            StorageKey::player(".BedrockPlayer").unwrap().key(),
+            "%2EBedrockPlayer"
+        );
+        // 编码后不同的玩家名不会映射到同一个键
+        assert_ne!(
+            StorageKey::player("A B").unwrap(),
+            StorageKey::player("A_B").unwrap()
+        );
+    }
+
+    #[test]
+    fn player_names_are_idempotent() {
+        for id in [
+            "Steve",
+            "A B",
+            ".x",
+            "CON",
+            "069A79F444E94726A5BEFCA90E38AAF5",
+        ] {
+            let key = StorageKey::player(id).unwrap();
+            assert_eq!(StorageKey::player(key.as_str()).unwrap(), key);
+        }
+        assert_eq!(StorageKey::player("A B").unwrap().as_str(), "A B");
+    }
+
+    #[test]
+    fn player_uuids_are_normalized() {
+        let expected = "069a79f4-44e9-4726-a5be-fca90e38aaf5";
+        for id in [
+            "069a79f444e94726a5befca90e38aaf5",
+            "069A79F444E94726A5BEFCA90E38AAF5",
+            "069a79f4-44e9-4726-a5be-fca90e38aaf5",
+            "069A79F4-44E9-4726-A5BE-FCA90E38AAF5",
+        ] {
+            assert_eq!(StorageKey::player(id).unwrap().key(), expected);
+        }
+        // 连字符位置错误的不是uuid, 按玩家名处理后因过长被拒绝
+        assert!(StorageKey::player("069a79f444e9-4726-a5be-fca90e38aaf5").is_err());
+    }
+
+    #[test]
+    fn player_rejects_hostile_input() {
+        for id in [
+            "",
+            ".",
+            "..",
+            "...",
+            "../Steve",
+            "..\\Steve",
+            "Steve/..",
+            "a/b",
+            "/etc/passwd",
+            "C:\\Windows",
+            "Steve..",
+            "%2e%2e",
+            "Steve\0",
+            "Steve\n",
+            " Steve",
+            "Steve ",
+            "Стив",
+            "Ｓｔｅｖｅ",
+            "Steve.nbt?x=1",
+            "*",
+            &"a".repeat(MAX_PLAYER_NAME_LEN + 1),
+        ] {
+            assert!(StorageKey::player(id).is_err(), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn player_keys_stay_in_storage_dir() {
+        let key = StorageKey::player(".x .y").unwrap();
+        let path = key.path();
+        assert_eq!(
+            path.parent().unwrap(),
+            std::path::Path::new(DIR_PATH_PLAYER_NBT)
+        );
+        assert!(!key.key().contains(['.', ' ', '/']));
+    }
+
+    #[test]
+    fn world_names() {
+        assert_eq!(StorageKey::world("survival_1").unwrap().key(), "survival_1");
+        for name in [
+            "",
+            "..",
+            "../world",
+            "world/../../etc",
+            "world.zip",
+            "my world",
+            "CON",
+            "con",
+            "Lpt1",
+            "aux",
+            "wörld",
+            &"w".repeat(MAX_WORLD_NAME_LEN + 1),
+        ] {
+            assert!(StorageKey::world(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn chunk_names() {
+        assert_eq!(StorageKey::chunk("r.0.-1").unwrap().key(), "r.0.-1");
+        assert_eq!(
+            StorageKey::chunk("overworld.12.34").unwrap().path(),
+            PathBuf::from(format!("{}/chunks/overworld.12.34.bin", DIR_PATH_WORLD))
+        );
+        for name in [
+            "",
+            ".",
+            "..",
+            ".hidden",
+            "a..b",
+            "../../chunk",
+            "chunks/0.0",
+            "nul.0.0",
+            "COM1.bin",
+            "0 0",
+            &"c".repeat(MAX_CHUNK_NAME_LEN + 1),
+        ] {
+            assert!(StorageKey::chunk(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn reserved_player_names_are_escaped() {
+        let key = StorageKey::player("CON").unwrap();
+        assert_eq!(key.kind, StorageKind::PlayerNbt);
+        assert_eq!(key.key(), "%43ON");
+        assert_eq!(StorageKey::player("com1").unwrap().key(), "%63om1");
+        assert_eq!(StorageKey::player("Console").unwrap().key(), "Console");
+        assert_ne!(
+            StorageKey::player("CON").unwrap(),
+            StorageKey::player("con").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn migrates_legacy_player_files() {
+        let dir = std::env::temp_dir().join(format!("storage-migrate-{}", std::process::id()));
+        let revisions = dir.join("revisions");
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        for sub in ["069A79F444E94726A5BEFCA90E38AAF5", "A B", "Steve"] {
+            tokio::fs::create_dir_all(revisions.join(sub))
+                .await
+                .unwrap();
+        }
+        let write = |path: PathBuf, data: &'static str| tokio::fs::write(path, data);
+        write(dir.join("A B.nbt"), "a").await.unwrap();
+        write(dir.join("A B.nbt.sha256"), "{}").await.unwrap();
+        write(dir.join("069A79F444E94726A5BEFCA90E38AAF5.nbt"), "u")
+            .await
+            .unwrap();
+        write(dir.join("Steve.nbt"), "s").await.unwrap();
+        // 已存在的目标不会被覆盖
+        write(dir.join("Steve.Jr.nbt"), "old").await.unwrap();
+        write(dir.join("Steve%2EJr.nbt"), "new").await.unwrap();
+        write(dir.join("Steve.nbt.upload-1"), "tmp").await.unwrap();
+        write(revisions.join("A B/1.nbt"), "r1").await.unwrap();
+        write(
+            revisions.join("069A79F444E94726A5BEFCA90E38AAF5/2.nbt"),
+            "r2",
+        )
+        .await
+        .unwrap();
+
+        let uuid = "069a79f4-44e9-4726-a5be-fca90e38aaf5";
+        let renamed = migrate_player_dir(&dir).await;
+        assert_eq!(
+            renamed,
+            [(
+                "069A79F444E94726A5BEFCA90E38AAF5".to_string(),
+                uuid.to_string()
+            )]
+        );
+        let read = |path: PathBuf| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(dir.join("A%20B.nbt")), "a");
+        assert_eq!(read(dir.join("A%20B.nbt.sha256")), "{}");
+        assert_eq!(read(dir.join(format!("{}.nbt", uuid))), "u");
+        assert_eq!(read(dir.join("Steve.nbt")), "s");
+        assert_eq!(read(dir.join("Steve.Jr.nbt")), "old");
+        assert_eq!(read(dir.join("Steve%2EJr.nbt")), "new");
+        assert_eq!(read(dir.join("Steve.nbt.upload-1")), "tmp");
+        assert_eq!(read(revisions.join("A%20B/1.nbt")), "r1");
+        assert_eq!(read(revisions.join(uuid).join("2.nbt")), "r2");
+        assert!(!dir.join("A B.nbt").exists());
+        assert!(!revisions.join("A B").exists());
+        assert!(revisions.join("Steve").exists());
+
+        // 再次执行没有变化
+        assert!(migrate_player_dir(&dir).await.is_empty());
+        assert_eq!(read(dir.join("A%20B.nbt")), "a");
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,621 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::ServerConfig,
+    sql::nbt_revision::{NbtMeta, NbtRevisionSqlite},
+};
+
+use super::{
+    chatserver::{ChatServer, DirectMessage},
+    file_stream::{receive_to_file, serve_file},
+    integrity::{commit_file_sync, expected_sha256, verify},
+    lease::Leases,
+    nbt,
+    player_api::ResponseMessage,
+    protocol::ServerFrame,
+    revision::{record_revision, RevisionSnapshot},
+    storage::StorageKey,
+};
+
+/// 保留的已结束转移记录数, 用于查询转移结果
+const FINISHED_LOG_SIZE: usize = 100;
+
+pub fn transfer_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/transfer")
+            .route("/begin/{token_path}", web::post().to(transfer_begin))
+            .route("/{id}/upload/{token_path}", web::post().to(transfer_upload))
+            .route("/{id}/claim/{token_path}", web::post().to(transfer_claim))
+            .route("/{id}/abort/{token_path}", web::post().to(transfer_abort))
+            .route("/{id}/{token_path}", web::get().to(transfer_get)),
+    );
+}
+
+/// 玩家转移状态
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferState {
+    /// 源服务端已锁定玩家, 等待上传数据
+    Locked,
+    /// 数据已上传并替换玩家nbt, 等待目标服务端领取
+    Ready,
+    /// 目标服务端已领取数据, 转移完成
+    Completed,
+    /// 转移中止或超时, 已解除锁定, 玩家仍归源服务端
+    /// 在`ready`之后回滚时玩家nbt保持为源服务端上传的数据, 不恢复到转移前
+    RolledBack,
+}
+
+/// 玩家转移记录
+/// player: 玩家名
+/// from: 源服务端
+/// to: 目标服务端
+/// created_at: 开始时间(unix秒)
+/// committed_at: 数据提交时间(unix秒), 提交后玩家nbt已被替换, 回滚不会撤销
+#[derive(Serialize, Debug, Clone)]
+pub struct Transfer {
+    pub id: u64,
+    pub player: String,
+    pub from: String,
+    pub to: String,
+    pub state: TransferState,
+    pub created_at: i64,
+    pub committed_at: Option<i64>,
+    /// 当前阶段的超时时间
+    #[serde(skip)]
+    deadline: Instant,
+}
+
+/// 转移操作失败的原因
+#[derive(Debug)]
+pub enum TransferError {
+    /// 转移不存在或已结束
+    NotFound,
+    /// 转移状态或服务端不符
+    Conflict(&'static str),
+}
+
+/// 玩家转移交接: 源服务端锁定玩家并上传数据, 数据提交后目标服务端才能领取
+/// 转移期间玩家nbt的普通读写被拒绝, 任一阶段超时则回滚
+/// 同时管理玩家数据租约, 转移完成后租约交给目标服务端
+pub struct TransferManager {
+    /// 进行中的转移, id -> 转移
+    transfers: HashMap<u64, Transfer>,
+    /// 玩家名 -> 进行中的转移id
+    players: HashMap<String, u64>,
+    finished: VecDeque<Transfer>,
+    next_id: u64,
+    timeout: Duration,
+    /// 用于通知源服务端和目标服务端转移状态变化
+    srv: Addr<ChatServer>,
+    pub(super) leases: Leases,
+}
+
+impl Actor for TransferManager {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(Duration::from_secs(1), |act, _| act.expire());
+    }
+}
+
+impl TransferManager {
+    pub fn new(config: &ServerConfig, srv: Addr<ChatServer>) -> TransferManager {
+        TransferManager {
+            transfers: HashMap::new(),
+            players: HashMap::new(),
+            finished: VecDeque::new(),
+            next_id: 0,
+            timeout: Duration::from_secs(config.transfer_timeout),
+            srv,
+            leases: Leases::new(config.nbt_lease_ttl, config.nbt_lease_required),
+        }
+    }
+
+    /// 玩家是否正在转移
+    pub fn is_transferring(&self, player: &str) -> bool {
+        self.players.contains_key(player)
+    }
+
+    /// 向源服务端和目标服务端发送转移状态
+    fn notify(&self, transfer: &Transfer) {
+        let text = ServerFrame::Transfer {
+            id: transfer.id,
+            player: &transfer.player,
+            from: &transfer.from,
+            to: &transfer.to,
+            state: transfer.state,
+        }
+        .to_text();
+        for server in [&transfer.from, &transfer.to] {
+            self.srv.do_send(DirectMessage {
+                server: server.clone(),
+                msg: text.clone(),
+            });
+        }
+    }
+
+    /// 取出进行中的转移, 检查状态和发起操作的服务端
+    fn active(
+        &mut self,
+        id: u64,
+        state: TransferState,
+        server: &str,
+    ) -> Result<&mut Transfer, TransferError> {
+        let transfer = self.transfers.get_mut(&id).ok_or(TransferError::NotFound)?;
+        if transfer.state != state {
+            return Err(TransferError::Conflict("转移状态不符"));
+        }
+        let expected = match state {
+            TransferState::Locked => &transfer.from,
+            _ => &transfer.to,
+        };
+        if server != expected {
+            return Err(TransferError::Conflict("服务端不符"));
+        }
+        Ok(transfer)
+    }
+
+    /// 结束转移并解除锁定
+    fn finish(&mut self, id: u64, state: TransferState) -> Option<Transfer> {
+        let mut transfer = self.transfers.remove(&id)?;
+        self.players.remove(&transfer.player);
+        transfer.state = state;
+        if state == TransferState::Completed {
+            self.leases.hand_over(&transfer.player, &transfer.to);
+        }
+        self.notify(&transfer);
+        self.finished.push_back(transfer.clone());
+        while self.finished.len() > FINISHED_LOG_SIZE {
+            self.finished.pop_front();
+        }
+        Some(transfer)
+    }
+
+    /// 回滚超时的转移, 清理到期的租约
+    fn expire(&mut self) {
+        self.leases.clear_expired();
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .transfers
+            .values()
+            .filter(|transfer| transfer.deadline <= now)
+            .map(|transfer| transfer.id)
+            .collect();
+        for id in expired {
+            if let Some(transfer) = self.finish(id, TransferState::RolledBack) {
+                log::warn!(
+                    "玩家 {} 从 {} 到 {} 的转移超时, 已回滚",
+                    transfer.player,
+                    transfer.from,
+                    transfer.to
+                );
+            }
+        }
+    }
+}
+
+/// 开始转移, 锁定玩家
+#[derive(Message)]
+#[rtype(result = "Result<Transfer, TransferError>")]
+pub struct TransferBegin {
+    pub player: String,
+    pub from: String,
+    pub to: String,
+}
+
+impl Handler<TransferBegin> for TransferManager {
+    type Result = Result<Transfer, TransferError>;
+
+    fn handle(&mut self, msg: TransferBegin, _: &mut Context<Self>) -> Self::Result {
+        if self.is_transferring(&msg.player) {
+            return Err(TransferError::Conflict("玩家正在转移中"));
+        }
+        if self
+            .leases
+            .holder(&msg.player)
+            .is_some_and(|lease| lease.server != msg.from)
+        {
+            return Err(TransferError::Conflict("租约由其他服务端持有"));
+        }
+        self.next_id += 1;
+        let transfer = Transfer {
+            id: self.next_id,
+            player: msg.player,
+            from: msg.from,
+            to: msg.to,
+            state: TransferState::Locked,
+            created_at: chrono::Local::now().timestamp(),
+            committed_at: None,
+            deadline: Instant::now() + self.timeout,
+        };
+        log::info!(
+            "玩家 {} 开始从 {} 转移到 {}",
+            transfer.player,
+            transfer.from,
+            transfer.to
+        );
+        self.players.insert(transfer.player.clone(), transfer.id);
+        self.transfers.insert(transfer.id, transfer.clone());
+        Ok(transfer)
+    }
+}
+
+/// 检查源服务端能否上传转移数据, 返回玩家名
+#[derive(Message)]
+#[rtype(result = "Result<String, TransferError>")]
+pub struct TransferUploadCheck {
+    pub id: u64,
+    pub server: String,
+}
+
+impl Handler<TransferUploadCheck> for TransferManager {
+    type Result = Result<String, TransferError>;
+
+    fn handle(&mut self, msg: TransferUploadCheck, _: &mut Context<Self>) -> Self::Result {
+        self.active(msg.id, TransferState::Locked, &msg.server)
+            .map(|transfer| transfer.player.clone())
+    }
+}
+
+/// 提交已上传到临时文件的转移数据, 替换玩家nbt并等待目标服务端领取
+/// 提交后转移被回滚时玩家nbt不会恢复, 转移已回滚时删除临时文件, 玩家nbt保持不变
+#[derive(Message)]
+#[rtype(result = "Result<Transfer, TransferError>")]
+pub struct TransferCommit {
+    pub id: u64,
+    pub server: String,
+    pub tmp: PathBuf,
+    pub path: PathBuf,
+    pub sha256: String,
+    pub meta: NbtMeta,
+}
+
+impl Handler<TransferCommit> for TransferManager {
+    type Result = Result<Transfer, TransferError>;
+
+    fn handle(&mut self, msg: TransferCommit, _: &mut Context<Self>) -> Self::Result {
+        let timeout = self.timeout;
+        let transfer = match self.active(msg.id, TransferState::Locked, &msg.server) {
+            Ok(transfer) => transfer,
+            Err(err) => {
+                let _ = std::fs::remove_file(&msg.tmp);
+                return Err(err);
+            }
+        };
+        match commit_file_sync(&msg.tmp, &msg.path, &msg.sha256) {
+            Ok(hash) => nbt::save_meta_sync(&msg.path, &hash.sha256, &msg.meta),
+            Err(err) => {
+                log::error!("玩家 {} 的转移数据提交失败: {}", transfer.player, err);
+                let _ = std::fs::remove_file(&msg.tmp);
+                return Err(TransferError::Conflict("数据提交失败"));
+            }
+        }
+        transfer.state = TransferState::Ready;
+        transfer.committed_at = Some(chrono::Local::now().timestamp());
+        transfer.deadline = Instant::now() + timeout;
+        let transfer = transfer.clone();
+        self.notify(&transfer);
+        Ok(transfer)
+    }
+}
+
+/// 目标服务端领取转移数据, 完成转移
+#[derive(Message)]
+#[rtype(result = "Result<Transfer, TransferError>")]
+pub struct TransferClaim {
+    pub id: u64,
+    pub server: String,
+}
+
+impl Handler<TransferClaim> for TransferManager {
+    type Result = Result<Transfer, TransferError>;
+
+    fn handle(&mut self, msg: TransferClaim, _: &mut Context<Self>) -> Self::Result {
+        self.active(msg.id, TransferState::Ready, &msg.server)?;
+        self.finish(msg.id, TransferState::Completed)
+            .ok_or(TransferError::NotFound)
+    }
+}
+
+/// 中止转移并回滚
+#[derive(Message)]
+#[rtype(result = "Option<Transfer>")]
+pub struct TransferAbort {
+    pub id: u64,
+}
+
+impl Handler<TransferAbort> for TransferManager {
+    type Result = Option<Transfer>;
+
+    fn handle(&mut self, msg: TransferAbort, _: &mut Context<Self>) -> Option<Transfer> {
+        self.finish(msg.id, TransferState::RolledBack)
+    }
+}
+
+/// 查询转移, 包括最近结束的转移
+#[derive(Message)]
+#[rtype(result = "Option<Transfer>")]
+pub struct TransferGet {
+    pub id: u64,
+}
+
+impl Handler<TransferGet> for TransferManager {
+    type Result = Option<Transfer>;
+
+    fn handle(&mut self, msg: TransferGet, _: &mut Context<Self>) -> Option<Transfer> {
+        self.transfers
+            .get(&msg.id)
+            .or_else(|| {
+                self.finished
+                    .iter()
+                    .rev()
+                    .find(|transfer| transfer.id == msg.id)
+            })
+            .cloned()
+    }
+}
+
+/// 查询玩家是否正在转移
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct TransferActive {
+    pub player: String,
+}
+
+impl Handler<TransferActive> for TransferManager {
+    type Result = bool;
+
+    fn handle(&mut self, msg: TransferActive, _: &mut Context<Self>) -> bool {
+        self.is_transferring(&msg.player)
+    }
+}
+
+fn error_response(err: TransferError) -> HttpResponse {
+    match err {
+        TransferError::NotFound => HttpResponse::NotFound().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "转移不存在或已结束".to_string(),
+        }),
+        TransferError::Conflict(message) => HttpResponse::Conflict().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: message.to_string(),
+        }),
+    }
+}
+
+/// 开始转移请求
+/// player: 玩家名
+/// from: 源服务端
+/// to: 目标服务端
+#[derive(Deserialize)]
+pub struct TransferRequest {
+    pub player: String,
+    pub from: String,
+    pub to: String,
+}
+
+// 源服务端开始转移, 锁定玩家
+pub async fn transfer_begin(
+    transfers: web::Data<Addr<TransferManager>>,
+    request: web::Json<TransferRequest>,
+    token_path: web::Path<String>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    if token_path.as_str() != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let request = request.into_inner();
+    let player = match StorageKey::player(&request.player) {
+        Ok(player) => player,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message: message.to_string(),
+            })
+        }
+    };
+    if request.from.is_empty() || request.to.is_empty() {
+        return HttpResponse::BadRequest().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "源服务端和目标服务端不能为空".to_string(),
+        });
+    }
+    if request.from == request.to {
+        return HttpResponse::BadRequest().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "源服务端和目标服务端不能相同".to_string(),
+        });
+    }
+    let msg = TransferBegin {
+        player: player.as_str().to_owned(),
+        from: request.from,
+        to: request.to,
+    };
+    match transfers.send(msg).await {
+        Ok(Ok(transfer)) => HttpResponse::Ok().json(transfer),
+        Ok(Err(err)) => error_response(err),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// 发起转移操作的服务端
+#[derive(Deserialize)]
+pub struct TransferServer {
+    pub server: String,
+}
+
+// 源服务端上传玩家数据, 上传完成后提交并通知目标服务端领取
+#[allow(clippy::too_many_arguments)]
+pub async fn transfer_upload(
+    req: HttpRequest,
+    transfers: web::Data<Addr<TransferManager>>,
+    path: web::Path<(u64, String)>,
+    query: web::Query<TransferServer>,
+    mut payload: web::Payload,
+    revisions: web::Data<NbtRevisionSqlite>,
+    config: web::Data<ServerConfig>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (id, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let server = query.into_inner().server;
+    let check = TransferUploadCheck {
+        id,
+        server: server.clone(),
+    };
+    let player = match transfers.send(check).await {
+        Ok(Ok(player)) => player,
+        Ok(Err(err)) => return error_response(err),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let Ok(player) = StorageKey::player(&player) else {
+        return HttpResponse::InternalServerError().finish();
+    };
+    let expected = match expected_sha256(&req) {
+        Ok(expected) => expected,
+        Err(response) => return response,
+    };
+    let path = player.path();
+    let tmp = path.with_extension(format!("nbt.transfer-{}", id));
+
+    let received = match receive_to_file(&req, &mut payload, &tmp, config.max_nbt_size).await {
+        Ok(received) => received,
+        Err(err) => return err.response(),
+    };
+    if let Err(response) = verify(expected.as_deref(), &received) {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return response;
+    }
+    let meta = match tokio::fs::read(&tmp).await.map(|bytes| nbt::parse(&bytes)) {
+        Ok(Ok(nbt)) => nbt.meta(),
+        Ok(Err(message)) => {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return HttpResponse::BadRequest().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message: message.to_string(),
+            });
+        }
+        Err(_) => {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    // 替换前从已校验的临时文件保存修订快照
+    let snapshot = RevisionSnapshot::take(&player, &tmp).await;
+    let commit = TransferCommit {
+        id,
+        server: server.clone(),
+        tmp,
+        path,
+        sha256: received.sha256,
+        meta: meta.clone(),
+    };
+    match transfers.send(commit).await {
+        Ok(Ok(transfer)) => {
+            if let Some(snapshot) = snapshot {
+                let recorded = record_revision(&revisions, &player, snapshot, &server, &meta, None);
+                if let Err(message) = recorded.await {
+                    log::warn!("玩家 {} 的转移数据已提交, 但{}", player.as_str(), message);
+                }
+            }
+            HttpResponse::Ok().json(transfer)
+        }
+        result => {
+            if let Some(snapshot) = snapshot {
+                snapshot.discard().await;
+            }
+            match result {
+                Ok(Err(err)) => error_response(err),
+                _ => HttpResponse::InternalServerError().finish(),
+            }
+        }
+    }
+}
+
+// 目标服务端领取玩家数据, 返回nbt文件并完成转移
+pub async fn transfer_claim(
+    req: HttpRequest,
+    transfers: web::Data<Addr<TransferManager>>,
+    path: web::Path<(u64, String)>,
+    query: web::Query<TransferServer>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (id, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let claim = TransferClaim {
+        id,
+        server: query.into_inner().server,
+    };
+    let transfer = match transfers.send(claim).await {
+        Ok(Ok(transfer)) => transfer,
+        Ok(Err(err)) => return error_response(err),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let Ok(player) = StorageKey::player(&transfer.player) else {
+        return HttpResponse::InternalServerError().finish();
+    };
+    serve_file(&req, &player.path()).await
+}
+
+// 中止转移并回滚, 玩家仍归源服务端
+// 数据已提交(committed_at不为空)时玩家nbt保持为源服务端上传的数据
+pub async fn transfer_abort(
+    transfers: web::Data<Addr<TransferManager>>,
+    path: web::Path<(u64, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (id, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    match transfers.send(TransferAbort { id }).await {
+        Ok(Some(transfer)) => HttpResponse::Ok().json(transfer),
+        Ok(None) => error_response(TransferError::NotFound),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// 查询转移状态
+pub async fn transfer_get(
+    transfers: web::Data<Addr<TransferManager>>,
+    path: web::Path<(u64, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (id, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    match transfers.send(TransferGet { id }).await {
+        Ok(Some(transfer)) => HttpResponse::Ok().json(transfer),
+        Ok(None) => error_response(TransferError::NotFound),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -0,0 +1,177 @@
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::sql::mailbox::MailboxSqlite;
+
+use super::{
+    chatserver::{ChatServer, DirectMessage},
+    moderation::WhisperCheck,
+    player::{PlayerManager, PlayerServerGet},
+    player_api::ResponseMessage,
+    protocol::ServerFrame,
+};
+
+/// 私信
+/// from: 发送玩家
+/// to: 接收玩家
+/// msg: 消息内容
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Whisper {
+    pub from: String,
+    pub to: String,
+    pub msg: String,
+}
+
+/// 私信投递结果
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WhisperStatus {
+    /// 已发送到玩家所在的服务端
+    Delivered,
+    /// 玩家不在线, 已存入离线信箱
+    Stored,
+    /// 玩家不在线
+    Offline,
+    /// 玩家不在线, 且离线信箱已满
+    MailboxFull,
+}
+
+/// 投递私信: 先经过聊天审核, 被拦截时返回拦截原因
+/// 查询接收玩家所在的服务端, 仅发送给该服务端的会话
+/// 玩家不在线且开启离线信箱时存入信箱, 信箱已满时不保存
+pub async fn deliver_whisper(
+    whisper: &Whisper,
+    players: &Addr<PlayerManager>,
+    srv: &Addr<ChatServer>,
+    mailbox: &MailboxSqlite,
+) -> Result<WhisperStatus, String> {
+    let check = WhisperCheck {
+        from: whisper.from.clone(),
+        msg: whisper.msg.clone(),
+    };
+    srv.send(check)
+        .await
+        .unwrap_or_else(|_| Err("聊天审核不可用".to_string()))?;
+    let server = players
+        .send(PlayerServerGet {
+            name: whisper.to.clone(),
+        })
+        .await
+        .ok()
+        .flatten();
+    if let Some(server) = server {
+        let msg = ServerFrame::Whisper {
+            from: &whisper.from,
+            to: &whisper.to,
+            msg: &whisper.msg,
+            time: chrono::Local::now().timestamp(),
+        }
+        .to_text();
+        if srv
+            .send(DirectMessage { server, msg })
+            .await
+            .unwrap_or(false)
+        {
+            return Ok(WhisperStatus::Delivered);
+        }
+    }
+    if !mailbox.enabled {
+        return Ok(WhisperStatus::Offline);
+    }
+    Ok(
+        match mailbox.add_mail(&whisper.to, &whisper.from, &whisper.msg) {
+            Ok(true) => WhisperStatus::Stored,
+            Ok(false) => WhisperStatus::MailboxFull,
+            Err(err) => {
+                log::error!("离线私信保存失败: {}", err);
+                WhisperStatus::Offline
+            }
+        },
+    )
+}
+
+/// 玩家上线时将离线信箱中的私信发送到玩家所在的服务端
+pub async fn deliver_mailbox(
+    player: &str,
+    server: &str,
+    srv: &Addr<ChatServer>,
+    mailbox: &MailboxSqlite,
+) {
+    if !mailbox.enabled {
+        return;
+    }
+    let mails = match mailbox.get_mail(player) {
+        Ok(mails) => mails,
+        Err(err) => {
+            log::error!("离线私信读取失败: {}", err);
+            return;
+        }
+    };
+    for mail in mails {
+        let msg = ServerFrame::Whisper {
+            from: &mail.sender,
+            to: &mail.recipient,
+            msg: &mail.msg,
+            time: mail.time,
+        }
+        .to_text();
+        let delivered = srv
+            .send(DirectMessage {
+                server: server.to_string(),
+                msg,
+            })
+            .await
+            .unwrap_or(false);
+        if !delivered {
+            break;
+        }
+        if let Err(err) = mailbox.delete_mail(mail.id) {
+            log::error!("离线私信删除失败: {}", err);
+        }
+    }
+}
+
+// 发送私信
+pub async fn post_whisper(
+    whisper: web::Json<Whisper>,
+    players: web::Data<Addr<PlayerManager>>,
+    srv: web::Data<Addr<ChatServer>>,
+    mailbox: web::Data<MailboxSqlite>,
+    token_path: web::Path<String>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    if token_path.as_str() != token.as_str() {
+        return HttpResponse::Unauthorized().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "token错误".to_string(),
+        });
+    }
+    let status = match deliver_whisper(&whisper, &players, &srv, &mailbox).await {
+        Ok(status) => status,
+        Err(reason) => {
+            return HttpResponse::Forbidden().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message: format!("私信被拦截: {}", reason),
+            })
+        }
+    };
+    match status {
+        WhisperStatus::Delivered => HttpResponse::Ok().json(ResponseMessage {
+            r#type: "success".to_string(),
+            message: "私信已送达".to_string(),
+        }),
+        WhisperStatus::Stored => HttpResponse::Accepted().json(ResponseMessage {
+            r#type: "success".to_string(),
+            message: "玩家不在线, 私信已存入离线信箱".to_string(),
+        }),
+        WhisperStatus::Offline => HttpResponse::NotFound().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "玩家不在线".to_string(),
+        }),
+        WhisperStatus::MailboxFull => HttpResponse::TooManyRequests().json(ResponseMessage {
+            r#type: "error".to_string(),
+            message: "玩家不在线, 离线信箱已满".to_string(),
+        }),
+    }
+}
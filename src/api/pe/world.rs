@@ -1,75 +1,132 @@
-use actix_web::{web, HttpResponse, HttpResponseBuilder};
-use futures_util::StreamExt;
+use actix_web::{
+    http::header::{EntityTag, ETAG},
+    web, HttpRequest, HttpResponse,
+};
 use log::info;
-use tokio::{fs::File, io::{AsyncReadExt, AsyncWriteExt}};
 
-use crate::DIR_PATH_WORLD;
+use crate::config::ServerConfig;
+
+use super::{
+    file_stream::{receive_to_file, serve_file, serve_meta, upload_tmp_path},
+    integrity::{commit_file, expected_sha256, verify, FileMeta},
+    player_api::ResponseMessage,
+    storage::StorageKey,
+    world_upload::{
+        world_upload_abort, world_upload_complete, world_upload_initiate, world_upload_part,
+        world_upload_status,
+    },
+};
 
 pub fn world_config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/world")
             .route("/{world}/upload/{token_path}", web::post().to(world_upload))
-            .route("/{world}/get/{token_path}", web::get().to(world_nbt_get)),
+            .route("/{world}/get/{token_path}", web::get().to(world_nbt_get))
+            .route("/{world}/get/{token_path}", web::head().to(world_nbt_get))
+            .route("/{world}/meta/{token_path}", web::get().to(world_meta_get))
+            // 分块上传
+            .route(
+                "/{world}/uploads/{token_path}",
+                web::post().to(world_upload_initiate),
+            )
+            .route(
+                "/uploads/{id}/{token_path}",
+                web::get().to(world_upload_status),
+            )
+            .route(
+                "/uploads/{id}/{token_path}",
+                web::delete().to(world_upload_abort),
+            )
+            .route(
+                "/uploads/{id}/parts/{part}/{token_path}",
+                web::put().to(world_upload_part),
+            )
+            .route(
+                "/uploads/{id}/complete/{token_path}",
+                web::post().to(world_upload_complete),
+            ),
     );
 }
 
-// 世界存档上传
+// 世界存档上传, 请求体流式写入临时文件, 接收完成后替换原存档
 pub async fn world_upload(
+    req: HttpRequest,
     //路径参数
-    path_world: web::Path<String>,
+    path: web::Path<(String, String)>,
     mut binary: web::Payload,
-    token_path: web::Path<String>,
+    config: web::Data<ServerConfig>,
     token: web::Data<String>,
-) -> HttpResponseBuilder {
-    if token_path.as_str() != token.as_str() {
-        return HttpResponse::Unauthorized();
+) -> HttpResponse {
+    let (world, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().finish();
     }
     info!("收到世界存档上传请求");
-    let player_name = path_world.as_str();
-
-    let file = format!("{}/{}.zip", DIR_PATH_WORLD, player_name);
+    let world = match StorageKey::world(&world) {
+        Ok(world) => world,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(ResponseMessage {
+                r#type: "error".to_string(),
+                message: message.to_string(),
+            })
+        }
+    };
 
-    //创建文件夹
-    let mut file = File::create(file).await.unwrap();
+    let expected = match expected_sha256(&req) {
+        Ok(expected) => expected,
+        Err(response) => return response,
+    };
 
-    //写入文件
-    let mut bytes = web::BytesMut::new();
-    while let Some(item) = binary.next().await {
-        bytes.extend_from_slice(&item.unwrap());
+    let path = world.path();
+    let tmp = upload_tmp_path(&path);
+    let received = match receive_to_file(&req, &mut binary, &tmp, config.max_world_size).await {
+        Ok(received) => received,
+        Err(err) => return err.response(),
+    };
+    if let Err(response) = verify(expected.as_deref(), &received) {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return response;
     }
-    match file.write_all(&bytes.to_vec()).await {
-        Ok(_) => HttpResponse::Ok(),
-        Err(err) => {
-            return HttpResponse::InternalServerError();
+    match commit_file(&tmp, &path, &received.sha256).await {
+        Ok(hash) => HttpResponse::Ok()
+            .insert_header((ETAG, EntityTag::new_strong(hash.sha256.clone())))
+            .json(FileMeta::from(hash)),
+        Err(_) => {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            HttpResponse::InternalServerError().finish()
         }
     }
 }
 
-// 世界存档获取
+// 世界存档获取, 支持Range请求续传和If-None-Match条件请求, HEAD请求只返回响应头
 pub async fn world_nbt_get(
+    req: HttpRequest,
     //路径参数
-    path_world: web::Path<String>,
-    token_path: web::Path<String>,
+    path: web::Path<(String, String)>,
     token: web::Data<String>,
 ) -> HttpResponse {
-    if token_path.as_str() != token.as_str() {
+    let (world, token_path) = path.into_inner();
+    if token_path != token.as_str() {
         return HttpResponse::NotFound().finish();
     }
     info!("收到世界存档获取请求");
-    let player_name = path_world.as_str();
-    let file = format!("{}/{}.zip", DIR_PATH_WORLD, player_name);
-    let file = File::open(file).await;
-    match file {
-        Ok(file) => {
-            let mut bytes = web::BytesMut::new();
-            let mut reader = tokio::io::BufReader::new(file);
-            let _ = reader.read_buf(&mut bytes).await.unwrap();
-            return HttpResponse::Ok()
-                .content_type("application/octet-stream")
-                .body(bytes.to_vec());
-        }
-        Err(err) => {
-            return HttpResponse::NotFound().finish();
-        }
+    let Ok(world) = StorageKey::world(&world) else {
+        return HttpResponse::BadRequest().finish();
+    };
+    serve_file(&req, &world.path()).await
+}
+
+// 世界存档的大小、SHA-256和修改时间
+pub async fn world_meta_get(
+    path: web::Path<(String, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (world, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::NotFound().finish();
     }
+    let Ok(world) = StorageKey::world(&world) else {
+        return HttpResponse::BadRequest().finish();
+    };
+    serve_meta(&world.path()).await
 }
@@ -0,0 +1,669 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{
+    http::header::{EntityTag, ETAG},
+    web, HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+use crate::{config::ServerConfig, DIR_PATH_WORLD};
+
+use super::{
+    file_stream::{receive_to_file, to_hex, upload_tmp_path, UploadError},
+    integrity::{commit_file, parse_sha256, FileMeta},
+    player_api::ResponseMessage,
+    storage::StorageKey,
+};
+
+/// 单次上传的最大分块数
+const MAX_PARTS: u64 = 10000;
+/// 分块校验和的请求头, 值为分块内容的SHA-256(十六进制)
+const PART_SHA256_HEADER: &str = "X-Part-Sha256";
+/// 上传信息文件名
+const MANIFEST: &str = "upload.json";
+/// 清理过期上传的间隔
+const SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+
+/// 分块上传的世界存档, 保存在{DIR_PATH_WORLD}/uploads/{id}/upload.json
+/// 分块n校验通过后保存为{n}.part, 校验和保存为{n}.sha256
+/// size: 存档总字节数
+/// part_size: 除最后一块外每块的字节数
+/// parts: 分块数, 分块编号从0开始
+/// sha256: 客户端声明的整个存档的SHA-256, 完成上传时校验
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorldUpload {
+    pub id: String,
+    pub world: String,
+    pub size: u64,
+    pub part_size: u64,
+    pub parts: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    pub created_at: i64,
+}
+
+impl WorldUpload {
+    fn dir(id: &str) -> PathBuf {
+        PathBuf::from(format!("{}/uploads/{}", DIR_PATH_WORLD, id))
+    }
+
+    fn part_path(&self, part: u64) -> PathBuf {
+        WorldUpload::dir(&self.id).join(format!("{}.part", part))
+    }
+
+    fn sha256_path(&self, part: u64) -> PathBuf {
+        WorldUpload::dir(&self.id).join(format!("{}.sha256", part))
+    }
+
+    /// 分块的字节数, 最后一块为剩余的字节数
+    fn part_len(&self, part: u64) -> u64 {
+        match part + 1 == self.parts {
+            true => self.size - self.part_size * part,
+            false => self.part_size,
+        }
+    }
+
+    /// 已接收并通过校验的分块
+    async fn received(&self) -> Vec<u64> {
+        let mut received = Vec::new();
+        for part in 0..self.parts {
+            if tokio::fs::try_exists(self.part_path(part))
+                .await
+                .unwrap_or(false)
+            {
+                received.push(part);
+            }
+        }
+        received
+    }
+}
+
+/// 初始化上传的请求体
+#[derive(Deserialize, Debug)]
+pub struct InitiateRequest {
+    pub size: u64,
+    pub part_size: u64,
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// 上传进度
+/// received: 已接收的分块
+/// missing: 尚未接收的分块
+#[derive(Serialize, Debug)]
+pub struct UploadStatus {
+    #[serde(flatten)]
+    pub upload: WorldUpload,
+    pub received: Vec<u64>,
+    pub missing: Vec<u64>,
+}
+
+/// 分块接收结果
+#[derive(Serialize, Debug)]
+pub struct PartReceived {
+    pub part: u64,
+    pub size: u64,
+    pub sha256: String,
+}
+
+fn error(mut response: HttpResponseBuilder, message: impl Into<String>) -> HttpResponse {
+    response.json(ResponseMessage {
+        r#type: "error".to_string(),
+        message: message.into(),
+    })
+}
+
+/// 上传id为32位小写十六进制, 校验后才拼接路径
+fn is_upload_id(id: &str) -> bool {
+    id.len() == 32 && id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+async fn load_upload(id: &str) -> Result<WorldUpload, HttpResponse> {
+    if !is_upload_id(id) {
+        return Err(error(HttpResponse::BadRequest(), "上传id错误"));
+    }
+    let manifest = tokio::fs::read(WorldUpload::dir(id).join(MANIFEST))
+        .await
+        .map_err(|_| error(HttpResponse::NotFound(), "上传不存在或已过期"))?;
+    serde_json::from_slice(&manifest)
+        .map_err(|_| error(HttpResponse::InternalServerError(), "上传信息损坏"))
+}
+
+/// 删除最后一次写入早于`expire`秒前的上传
+async fn remove_expired(expire: u64) {
+    let Ok(mut dir) = tokio::fs::read_dir(format!("{}/uploads", DIR_PATH_WORLD)).await else {
+        return;
+    };
+    let Some(deadline) = SystemTime::now().checked_sub(Duration::from_secs(expire)) else {
+        return;
+    };
+    while let Ok(Some(entry)) = dir.next_entry().await {
+        // 分块重命名进目录时会更新目录的修改时间
+        let expired = entry
+            .metadata()
+            .await
+            .and_then(|meta| meta.modified())
+            .is_ok_and(|modified| modified < deadline);
+        if expired {
+            info!("删除过期的世界存档上传: {:?}", entry.file_name());
+            let _ = tokio::fs::remove_dir_all(entry.path()).await;
+        }
+    }
+}
+
+/// 启动时以及之后每隔`SWEEP_INTERVAL`删除过期的上传, 不再有新上传时也会清理中断上传的分块
+pub fn spawn_expire_sweep(expire: u64) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            remove_expired(expire).await;
+        }
+    });
+}
+
+// 开始分块上传世界存档, 返回上传id
+pub async fn world_upload_initiate(
+    path: web::Path<(String, String)>,
+    body: web::Json<InitiateRequest>,
+    config: web::Data<ServerConfig>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (world, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let world = match StorageKey::world(&world) {
+        Ok(world) => world,
+        Err(message) => return error(HttpResponse::BadRequest(), message),
+    };
+    let InitiateRequest {
+        size,
+        part_size,
+        sha256,
+    } = body.into_inner();
+    if size == 0 || part_size == 0 {
+        return error(HttpResponse::BadRequest(), "文件大小和分块大小不能为0");
+    }
+    let sha256 = match sha256.as_deref().map(parse_sha256) {
+        None => None,
+        Some(Some(sha256)) => Some(sha256),
+        Some(None) => return error(HttpResponse::BadRequest(), "sha256应为64位十六进制"),
+    };
+    if size > config.max_world_size {
+        return UploadError::TooLarge(config.max_world_size).response();
+    }
+    if part_size > config.max_world_part_size {
+        return error(
+            HttpResponse::BadRequest(),
+            format!("分块超过大小限制({}字节)", config.max_world_part_size),
+        );
+    }
+    let parts = size.div_ceil(part_size);
+    if parts > MAX_PARTS {
+        return error(
+            HttpResponse::BadRequest(),
+            format!("分块数超过限制({})", MAX_PARTS),
+        );
+    }
+    remove_expired(config.world_upload_expire).await;
+
+    let upload = WorldUpload {
+        id: to_hex(&rand::random::<[u8; 16]>()),
+        world: world.as_str().to_string(),
+        size,
+        part_size,
+        parts,
+        sha256,
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    let dir = WorldUpload::dir(&upload.id);
+    let manifest = serde_json::to_vec(&upload).unwrap_or_default();
+    if tokio::fs::create_dir_all(&dir).await.is_err()
+        || tokio::fs::write(dir.join(MANIFEST), manifest)
+            .await
+            .is_err()
+    {
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        return UploadError::Io.response();
+    }
+    info!(
+        "开始分块上传世界存档: {} {} ({}字节, {}块)",
+        upload.world, upload.id, size, parts
+    );
+    HttpResponse::Ok().json(UploadStatus {
+        missing: (0..parts).collect(),
+        received: Vec::new(),
+        upload,
+    })
+}
+
+// 查询上传进度, 断线后按missing续传
+pub async fn world_upload_status(
+    path: web::Path<(String, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (id, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let upload = match load_upload(&id).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    let received = upload.received().await;
+    let missing = (0..upload.parts)
+        .filter(|part| !received.contains(part))
+        .collect();
+    HttpResponse::Ok().json(UploadStatus {
+        upload,
+        received,
+        missing,
+    })
+}
+
+// 上传分块, 请求头X-Part-Sha256为分块的SHA-256, 长度和校验和都正确时才保存
+// 重复上传同一分块会覆盖之前的内容
+pub async fn world_upload_part(
+    req: HttpRequest,
+    path: web::Path<(String, u64, String)>,
+    mut payload: web::Payload,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (id, part, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let upload = match load_upload(&id).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    if part >= upload.parts {
+        return error(
+            HttpResponse::BadRequest(),
+            format!("分块编号超出范围(0~{})", upload.parts - 1),
+        );
+    }
+    let Some(expected) = req
+        .headers()
+        .get(PART_SHA256_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_sha256)
+    else {
+        return error(
+            HttpResponse::BadRequest(),
+            format!("缺少{}请求头或格式错误", PART_SHA256_HEADER),
+        );
+    };
+
+    let part_path = upload.part_path(part);
+    let tmp = upload_tmp_path(&part_path);
+    let len = upload.part_len(part);
+    let received = match receive_to_file(&req, &mut payload, &tmp, len).await {
+        Ok(received) => received,
+        Err(err) => return err.response(),
+    };
+    let mismatch = if received.size != len {
+        Some(format!("分块长度错误, 应为{}字节", len))
+    } else if received.sha256 != expected {
+        Some("分块校验和不匹配".to_string())
+    } else {
+        None
+    };
+    if let Some(message) = mismatch {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return error(HttpResponse::BadRequest(), message);
+    }
+    // 先写校验和再重命名分块, 分块文件存在时校验和一定存在
+    let saved = match tokio::fs::write(upload.sha256_path(part), &received.sha256).await {
+        Ok(_) => tokio::fs::rename(&tmp, &part_path).await,
+        Err(err) => Err(err),
+    };
+    if saved.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return UploadError::Io.response();
+    }
+    HttpResponse::Ok().json(PartReceived {
+        part,
+        size: received.size,
+        sha256: received.sha256,
+    })
+}
+
+/// 将分块依次追加到`out`, 同时重新计算分块和整个存档的校验和
+/// 返回长度或校验和与接收时不一致的分块
+async fn append_part(
+    upload: &WorldUpload,
+    part: u64,
+    out: &mut File,
+    total: &mut Sha256,
+) -> std::io::Result<Result<(), u64>> {
+    let expected = tokio::fs::read_to_string(upload.sha256_path(part)).await?;
+    let mut file = File::open(upload.part_path(part)).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        size += n as u64;
+        hasher.update(&buf[..n]);
+        total.update(&buf[..n]);
+        out.write_all(&buf[..n]).await?;
+    }
+    if size != upload.part_len(part) || to_hex(&hasher.finalize()) != expected.trim() {
+        return Ok(Err(part));
+    }
+    Ok(Ok(()))
+}
+
+/// 拼接所有分块, 返回整个存档的SHA-256
+async fn assemble(upload: &WorldUpload, tmp: &Path) -> std::io::Result<Result<String, u64>> {
+    let mut out = File::create(tmp).await?;
+    let mut total = Sha256::new();
+    for part in 0..upload.parts {
+        if let Err(part) = append_part(upload, part, &mut out, &mut total).await? {
+            return Ok(Err(part));
+        }
+    }
+    out.flush().await?;
+    Ok(Ok(to_hex(&total.finalize())))
+}
+
+// 完成上传: 所有分块都已接收时重新校验并按顺序拼接到临时文件, 全部通过后替换世界存档
+// 校验失败的分块会被删除, 需要重新上传; 与声明的整个存档的SHA-256不一致时保留分块
+pub async fn world_upload_complete(
+    path: web::Path<(String, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (id, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let upload = match load_upload(&id).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    let received = upload.received().await;
+    if received.len() as u64 != upload.parts {
+        let missing: Vec<u64> = (0..upload.parts)
+            .filter(|part| !received.contains(part))
+            .collect();
+        return error(
+            HttpResponse::Conflict(),
+            format!("分块未全部上传, 缺少: {:?}", missing),
+        );
+    }
+    let Ok(world) = StorageKey::world(&upload.world) else {
+        return error(HttpResponse::InternalServerError(), "上传信息损坏");
+    };
+
+    let path = world.path();
+    let tmp = upload_tmp_path(&path);
+    let sha256 = match assemble(&upload, &tmp).await {
+        Ok(Ok(sha256)) => sha256,
+        Ok(Err(part)) => {
+            warn!("世界存档上传 {} 的分块{}校验失败", upload.id, part);
+            let _ = tokio::fs::remove_file(&tmp).await;
+            let _ = tokio::fs::remove_file(upload.part_path(part)).await;
+            return error(
+                HttpResponse::Conflict(),
+                format!("分块{}校验失败, 请重新上传", part),
+            );
+        }
+        Err(_) => {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return UploadError::Io.response();
+        }
+    };
+    if upload
+        .sha256
+        .as_ref()
+        .is_some_and(|expected| *expected != sha256)
+    {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return error(
+            HttpResponse::Conflict(),
+            format!("文件校验和不匹配, 实际为{}", sha256),
+        );
+    }
+    let hash = match commit_file(&tmp, &path, &sha256).await {
+        Ok(hash) => hash,
+        Err(_) => {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return UploadError::Io.response();
+        }
+    };
+    let _ = tokio::fs::remove_dir_all(WorldUpload::dir(&upload.id)).await;
+    info!("世界存档 {} 分块上传完成: {}", upload.world, upload.id);
+    HttpResponse::Ok()
+        .insert_header((ETAG, EntityTag::new_strong(hash.sha256.clone())))
+        .json(FileMeta::from(hash))
+}
+
+// 取消上传并删除已上传的分块
+pub async fn world_upload_abort(
+    path: web::Path<(String, String)>,
+    token: web::Data<String>,
+) -> HttpResponse {
+    let (id, token_path) = path.into_inner();
+    if token_path != token.as_str() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let upload = match load_upload(&id).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    match tokio::fs::remove_dir_all(WorldUpload::dir(&upload.id)).await {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(_) => UploadError::Io.response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        body::to_bytes,
+        http::StatusCode,
+        test::{self, TestRequest},
+        App,
+    };
+
+    use super::*;
+
+    const TOKEN: &str = "test-token";
+
+    /// 直接写入上传信息, 创建分块上传
+    async fn create(
+        world: &str,
+        content: &[u8],
+        part_size: u64,
+        sha256: Option<String>,
+    ) -> WorldUpload {
+        let upload = WorldUpload {
+            id: to_hex(&rand::random::<[u8; 16]>()),
+            world: world.to_string(),
+            size: content.len() as u64,
+            part_size,
+            parts: (content.len() as u64).div_ceil(part_size),
+            sha256,
+            created_at: 0,
+        };
+        let dir = WorldUpload::dir(&upload.id);
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join(MANIFEST), serde_json::to_vec(&upload).unwrap())
+            .await
+            .unwrap();
+        upload
+    }
+
+    /// 上传分块, 返回状态码和响应内容
+    async fn put_part(
+        upload: &WorldUpload,
+        part: u64,
+        data: &[u8],
+        sha256: &str,
+    ) -> (StatusCode, String) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(TOKEN.to_string()))
+                .route(
+                    "/uploads/{id}/parts/{part}/{token_path}",
+                    web::put().to(world_upload_part),
+                ),
+        )
+        .await;
+        let req = TestRequest::put()
+            .uri(&format!("/uploads/{}/parts/{}/{}", upload.id, part, TOKEN))
+            .insert_header((PART_SHA256_HEADER, sha256))
+            .set_payload(data.to_vec())
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        let status = response.status();
+        let body = to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    async fn complete(upload: &WorldUpload) -> (StatusCode, String) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(TOKEN.to_string()))
+                .route(
+                    "/uploads/{id}/complete/{token_path}",
+                    web::post().to(world_upload_complete),
+                ),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri(&format!("/uploads/{}/complete/{}", upload.id, TOKEN))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        let status = response.status();
+        let body = to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        to_hex(&Sha256::digest(data))
+    }
+
+    /// 上传目录中除上传信息外的文件
+    async fn files(upload: &WorldUpload) -> Vec<String> {
+        let mut files = Vec::new();
+        let mut dir = tokio::fs::read_dir(WorldUpload::dir(&upload.id))
+            .await
+            .unwrap();
+        while let Some(entry) = dir.next_entry().await.unwrap() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name != MANIFEST {
+                files.push(name);
+            }
+        }
+        files.sort();
+        files
+    }
+
+    async fn upload_all(upload: &WorldUpload, content: &[u8]) {
+        for (part, data) in content.chunks(upload.part_size as usize).enumerate() {
+            let (status, _) = put_part(upload, part as u64, data, &sha256(data)).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+    }
+
+    #[actix_web::test]
+    async fn rejects_bad_parts() {
+        let upload = create("test-bad-parts", b"0123456789", 4, None).await;
+
+        let (status, body) = put_part(&upload, 0, b"012", &sha256(b"012")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("分块长度错误"));
+
+        let (status, _) = put_part(&upload, 0, b"01234", &sha256(b"01234")).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let (status, body) = put_part(&upload, 0, b"0123", &sha256(b"abcd")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("分块校验和不匹配"));
+
+        let (status, _) = put_part(&upload, 0, b"0123", "not-a-sha256").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = put_part(&upload, 3, b"", &sha256(b"")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(files(&upload).await.is_empty());
+
+        let (status, _) = put_part(&upload, 2, b"89", &sha256(b"89")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(files(&upload).await, ["2.part", "2.sha256"]);
+
+        tokio::fs::remove_dir_all(WorldUpload::dir(&upload.id))
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn complete_deletes_corrupted_part() {
+        let content = b"0123456789";
+        let upload = create("test-corrupted-part", content, 4, None).await;
+        upload_all(&upload, content).await;
+        // 保存后被改动的分块在拼接时重新校验
+        tokio::fs::write(upload.part_path(1), b"xxxx")
+            .await
+            .unwrap();
+
+        let (status, body) = complete(&upload).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body.contains("分块1校验失败"));
+        assert_eq!(
+            files(&upload).await,
+            ["0.part", "0.sha256", "1.sha256", "2.part", "2.sha256"]
+        );
+        let world = StorageKey::world(&upload.world).unwrap();
+        assert!(!tokio::fs::try_exists(world.path()).await.unwrap());
+
+        tokio::fs::remove_dir_all(WorldUpload::dir(&upload.id))
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn complete_rejects_hash_mismatch() {
+        let content = b"0123456789";
+        let upload = create("test-hash-mismatch", content, 4, Some(sha256(b"other"))).await;
+        upload_all(&upload, content).await;
+
+        let (status, body) = complete(&upload).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body.contains(&format!("文件校验和不匹配, 实际为{}", sha256(content))));
+        // 分块本身无误, 保留以便重新发起上传
+        assert_eq!(upload.received().await, [0, 1, 2]);
+        let world = StorageKey::world(&upload.world).unwrap();
+        assert!(!tokio::fs::try_exists(world.path()).await.unwrap());
+
+        tokio::fs::remove_dir_all(WorldUpload::dir(&upload.id))
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn remove_expired_with_large_expire() {
+        let upload = create("test-expire", b"0123456789", 4, None).await;
+        remove_expired(u64::MAX).await;
+        assert!(load_upload(&upload.id).await.is_ok());
+
+        tokio::fs::remove_dir_all(WorldUpload::dir(&upload.id))
+            .await
+            .unwrap();
+    }
+}
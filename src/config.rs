@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Write};
 
@@ -9,6 +10,27 @@ use std::io::{Read, Write};
 /// money_mode: 单机模式/多机模式
 /// sql_mode: sqlite/postgres/mongodb
 /// postgres_config: Postgres配置
+/// servers: 游戏服独立凭据, 服务端名 -> token, 配置后该服务端不再接受全局token, 未配置的服务端使用全局token
+/// heartbeat_interval: WebSocket心跳间隔(秒), 须大于0
+/// client_timeout: WebSocket客户端超时时间(秒), 超过该时间未收到任何帧则断开, 须大于心跳间隔
+/// ws_max_frame_size: WebSocket单条消息(含分片合并后)的最大字节数
+/// ws_compress_threshold: 开启压缩的会话中, 超过该字节数的文本帧以deflate压缩后发送
+/// chat_history_size: 每个频道保留的聊天记录条数, 最少为1
+/// chat_history_persist: 是否将聊天记录保存到sqlite, 重启后恢复
+/// mailbox_enabled: 是否开启离线信箱, 开启后发给离线玩家的私信在其上线时送达
+/// mailbox: 离线信箱容量和保留策略
+/// moderation: 聊天审核配置
+/// delivery: 消息确认投递配置
+/// transfer_timeout: 玩家转移每个阶段(上传数据、目标服务端领取)的超时时间(秒), 超时后回滚
+/// nbt_lease_ttl: 玩家数据租约的默认租期(秒)
+/// nbt_lease_max_ttl: 获取或续约时可以指定的最长租期(秒)
+/// nbt_lease_required: 写入玩家nbt是否必须持有租约, 关闭时只拒绝其他服务端持有租约的写入
+/// nbt_revisions: 玩家nbt修订保留策略
+/// max_nbt_size: 上传玩家nbt的最大字节数
+/// max_world_size: 上传世界存档的最大字节数
+/// max_world_part_size: 分块上传世界存档时单个分块的最大字节数
+/// world_upload_expire: 分块上传在最后一次写入后保留的时间(秒), 过期后删除已上传的分块
+/// max_command_timeout: 远程命令等待结果的最长时间(秒), 请求的等待时间超过该值时拒绝
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub v4port: u16,
@@ -17,6 +39,125 @@ pub struct ServerConfig {
     pub money_mode: String,
     pub sql_mode: String,
     pub postgres_config: PostgresConfig,
+    #[serde(default)]
+    pub servers: HashMap<String, String>,
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    #[serde(default = "default_client_timeout")]
+    pub client_timeout: u64,
+    #[serde(default = "default_ws_max_frame_size")]
+    pub ws_max_frame_size: usize,
+    #[serde(default = "default_ws_compress_threshold")]
+    pub ws_compress_threshold: usize,
+    #[serde(default = "default_chat_history_size")]
+    pub chat_history_size: usize,
+    #[serde(default)]
+    pub chat_history_persist: bool,
+    #[serde(default)]
+    pub mailbox_enabled: bool,
+    #[serde(default)]
+    pub mailbox: MailboxConfig,
+    #[serde(default)]
+    pub moderation: ModerationConfig,
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+    #[serde(default = "default_transfer_timeout")]
+    pub transfer_timeout: u64,
+    #[serde(default = "default_nbt_lease_ttl")]
+    pub nbt_lease_ttl: u64,
+    #[serde(default = "default_nbt_lease_max_ttl")]
+    pub nbt_lease_max_ttl: u64,
+    #[serde(default)]
+    pub nbt_lease_required: bool,
+    #[serde(default)]
+    pub nbt_revisions: NbtRevisionConfig,
+    #[serde(default = "default_max_nbt_size")]
+    pub max_nbt_size: u64,
+    #[serde(default = "default_max_world_size")]
+    pub max_world_size: u64,
+    #[serde(default = "default_max_world_part_size")]
+    pub max_world_part_size: u64,
+    #[serde(default = "default_world_upload_expire")]
+    pub world_upload_expire: u64,
+    #[serde(default = "default_max_command_timeout")]
+    pub max_command_timeout: u64,
+}
+
+fn default_heartbeat_interval() -> u64 {
+    5
+}
+
+fn default_client_timeout() -> u64 {
+    15
+}
+
+fn default_ws_max_frame_size() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_ws_compress_threshold() -> usize {
+    1024
+}
+
+fn default_chat_history_size() -> usize {
+    100
+}
+
+fn default_transfer_timeout() -> u64 {
+    30
+}
+
+fn default_nbt_lease_ttl() -> u64 {
+    60
+}
+
+fn default_nbt_lease_max_ttl() -> u64 {
+    3600
+}
+
+fn default_max_nbt_size() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_max_world_size() -> u64 {
+    4 * 1024 * 1024 * 1024
+}
+
+fn default_max_world_part_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_world_upload_expire() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_max_command_timeout() -> u64 {
+    300
+}
+
+impl ServerConfig {
+    /// 检查心跳配置, 心跳间隔为0或客户端超时不大于心跳间隔时使用默认值
+    fn check_heartbeat(&mut self) {
+        if self.heartbeat_interval == 0 || self.client_timeout <= self.heartbeat_interval {
+            log::warn!(
+                "心跳配置无效(heartbeat_interval: {}, client_timeout: {}), 使用默认值({}, {})",
+                self.heartbeat_interval,
+                self.client_timeout,
+                default_heartbeat_interval(),
+                default_client_timeout()
+            );
+            self.heartbeat_interval = default_heartbeat_interval();
+            self.client_timeout = default_client_timeout();
+        }
+    }
+
+    /// 校验游戏服连接凭据, 配置了独立token的服务端只接受独立token, 其他服务端使用全局token
+    pub fn check_server_token(&self, server_name: &str, token: &str) -> bool {
+        match self.servers.get(server_name) {
+            Some(server_token) => server_token == token,
+            None => token == self.token,
+        }
+    }
 }
 
 /// Postgres配置
@@ -34,6 +175,113 @@ pub struct PostgresConfig {
     pub database: String,
 }
 
+/// 聊天审核配置
+/// blocked_words: 屏蔽词, 不区分大小写
+/// blocked_patterns: 屏蔽正则表达式
+/// flood_max_messages: 刷屏检测窗口内允许的最大消息数, 0为不检测
+/// flood_window: 刷屏检测窗口(秒)
+/// slow_mode: 频道慢速模式, 频道名 -> 同一发送者两条消息的最小间隔(秒)
+/// blocked_log_size: 保留的拦截记录条数
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModerationConfig {
+    pub blocked_words: Vec<String>,
+    pub blocked_patterns: Vec<String>,
+    pub flood_max_messages: usize,
+    pub flood_window: u64,
+    pub slow_mode: HashMap<String, u64>,
+    pub blocked_log_size: usize,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            blocked_words: Vec::new(),
+            blocked_patterns: Vec::new(),
+            flood_max_messages: 5,
+            flood_window: 5,
+            slow_mode: HashMap::new(),
+            blocked_log_size: 200,
+        }
+    }
+}
+
+/// 发送队列已满时的处理方式
+/// drop_oldest: 丢弃最早未确认或未发出的消息
+/// disconnect: 断开该会话
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    DropOldest,
+    Disconnect,
+}
+
+/// 消息投递配置, 重发相关配置仅对连接时开启确认的会话生效
+/// max_queue: 每个会话最多未确认的消息数, 以及客户端读取过慢时最多未发出的消息数
+/// overflow: 队列已满时的处理方式
+/// retry_initial: 首次重发等待时间(毫秒), 之后每次翻倍
+/// retry_max: 最大重发等待时间(毫秒)
+/// max_attempts: 最多发送次数, 超过后丢弃该消息
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeliveryConfig {
+    pub max_queue: usize,
+    pub overflow: OverflowPolicy,
+    pub retry_initial: u64,
+    pub retry_max: u64,
+    pub max_attempts: u32,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        Self {
+            max_queue: 256,
+            overflow: OverflowPolicy::DropOldest,
+            retry_initial: 1000,
+            retry_max: 30000,
+            max_attempts: 10,
+        }
+    }
+}
+
+/// 离线信箱容量和保留策略
+/// max_per_recipient: 每个玩家最多保存的离线私信数, 信箱已满时拒绝新私信, 0为不限
+/// keep_days: 离线私信保留天数, 0为不限
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MailboxConfig {
+    pub max_per_recipient: usize,
+    pub keep_days: u64,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        Self {
+            max_per_recipient: 100,
+            keep_days: 30,
+        }
+    }
+}
+
+/// 玩家nbt修订保留策略, 两项同时生效, 每个玩家最新的修订总是保留
+/// keep_last: 每个玩家保留的修订数, 0为不限
+/// keep_days: 修订保留天数, 0为不限
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NbtRevisionConfig {
+    pub keep_last: usize,
+    pub keep_days: u64,
+}
+
+impl Default for NbtRevisionConfig {
+    fn default() -> Self {
+        Self {
+            keep_last: 20,
+            keep_days: 30,
+        }
+    }
+}
+
 /// 默认配置
 /// 默认配置文件路径为config.yml
 ///  v4port: 2000
@@ -52,6 +300,27 @@ impl Default for ServerConfig {
             money_mode: "single/multi".to_string(),
             sql_mode: "sqlite/postgres/mongodb".to_string(),
             postgres_config:PostgresConfig::default(),
+            servers: HashMap::new(),
+            heartbeat_interval: default_heartbeat_interval(),
+            client_timeout: default_client_timeout(),
+            ws_max_frame_size: default_ws_max_frame_size(),
+            ws_compress_threshold: default_ws_compress_threshold(),
+            chat_history_size: default_chat_history_size(),
+            chat_history_persist: false,
+            mailbox_enabled: false,
+            mailbox: MailboxConfig::default(),
+            moderation: ModerationConfig::default(),
+            delivery: DeliveryConfig::default(),
+            transfer_timeout: default_transfer_timeout(),
+            nbt_lease_ttl: default_nbt_lease_ttl(),
+            nbt_lease_max_ttl: default_nbt_lease_max_ttl(),
+            nbt_lease_required: false,
+            nbt_revisions: NbtRevisionConfig::default(),
+            max_nbt_size: default_max_nbt_size(),
+            max_world_size: default_max_world_size(),
+            max_world_part_size: default_max_world_part_size(),
+            world_upload_expire: default_world_upload_expire(),
+            max_command_timeout: default_max_command_timeout(),
         };
         match read_yml(&file_path) {
             Ok(config) => config,
@@ -109,7 +378,8 @@ pub fn read_yml(file_path: &str) -> Result<ServerConfig, Box<dyn std::error::Err
     let mut file = File::open(file_path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
-    let config: ServerConfig = serde_yaml::from_str(&contents)?;
+    let mut config: ServerConfig = serde_yaml::from_str(&contents)?;
+    config.check_heartbeat();
     Ok(config)
 }
 
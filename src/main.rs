@@ -12,11 +12,16 @@ use rusqlite::Connection;
 
 use crate::{
     api::{money::money::money_config, pe::{
-        player::PlayerManager, player_api::player_config, session::chatserver_config,
-        world::world_config,
+        command::command_config, lease::lease_config, moderation::moderation_config, player::PlayerManager, player_api::player_config,
+        session::chatserver_config, storage,
+        transfer::{transfer_config, TransferManager},
+        world::world_config, world_upload,
     }},
     config::ServerConfig,
-    sql::{multi_economy::MultiMoneySqlite, single_economy::SingleMoneySqlite},
+    sql::{
+        mailbox::MailboxSqlite, multi_economy::MultiMoneySqlite, nbt_revision::NbtRevisionSqlite,
+        single_economy::SingleMoneySqlite,
+    },
 };
 
 mod config;
@@ -39,8 +44,9 @@ async fn main() -> Result<(), std::io::Error> {
     let config = ServerConfig::default();
 
     // start chat server actor
-    let server = chatserver::ChatServer::new().start();
-    let players = PlayerManager::new().start();
+    let server = chatserver::ChatServer::new(&config).start();
+    let players = PlayerManager::new(server.clone()).start();
+    let transfers = TransferManager::new(&config, server.clone()).start();
 
     // println!("{}", t!("messages.hello","name" => "world", locale => "zh-CN"));
 
@@ -65,18 +71,42 @@ async fn main() -> Result<(), std::io::Error> {
     let multi_money_sqlite = MultiMoneySqlite::default();
     multi_money_sqlite.init().await;
 
+    let mailbox = MailboxSqlite::init(
+        config.mailbox_enabled,
+        config.mailbox.max_per_recipient,
+        config.mailbox.keep_days,
+    )
+    .await;
+    let revisions = NbtRevisionSqlite::init(
+        config.nbt_revisions.keep_last,
+        config.nbt_revisions.keep_days,
+    )
+    .await;
+
+    storage::migrate_legacy_files(&revisions).await;
+    world_upload::spawn_expire_sweep(config.world_upload_expire);
+
     let token = config.token.clone();
+    let server_config = config.clone();
     HttpServer::new(move || {
         App::new()
+            .app_data(web::Data::new(server_config.clone()))
             .app_data(web::Data::new(server.clone()))
             .app_data(web::Data::new(players.clone()))
+            .app_data(web::Data::new(transfers.clone()))
             .app_data(web::Data::new(token.clone()))
             .app_data(web::Data::new(single_money_sqlite.clone()))
             .app_data(web::Data::new(multi_money_sqlite.clone()))
+            .app_data(web::Data::new(mailbox.clone()))
+            .app_data(web::Data::new(revisions.clone()))
             .service(
                 web::scope("/api/pe")
                     .configure(player_config)
                     .configure(chatserver_config)
+                    .configure(moderation_config)
+                    .configure(command_config)
+                    .configure(transfer_config)
+                    .configure(lease_config)
                     .configure(world_config),
             )
             .service(
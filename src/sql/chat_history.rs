@@ -0,0 +1,65 @@
+use rusqlite::{params, Connection};
+
+use crate::DIR_PATH_SQLITE;
+
+/// 聊天历史记录
+/// seq: 频道内递增序号
+/// channel: 频道名
+/// msg: 消息内容
+/// time: 发送时间(unix秒)
+#[derive(Debug, Clone)]
+pub struct ChatHistoryEntry {
+    pub seq: u64,
+    pub channel: String,
+    pub msg: String,
+    pub time: i64,
+}
+
+/// 聊天历史持久化, 保存在chat.db
+#[derive(Debug, Clone)]
+pub struct ChatHistorySqlite {
+    /// 每个频道保留的消息条数
+    pub capacity: usize,
+}
+
+impl ChatHistorySqlite {
+    /// 初始化, 创建chat.db
+    pub fn init(capacity: usize) -> Result<Self, rusqlite::Error> {
+        std::fs::create_dir_all(DIR_PATH_SQLITE).ok();
+        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/chat.db")?;
+        let stmt = include_str!("../sql/sqlite/chat_history/init.sql");
+        conn.execute_batch(stmt)?;
+        Ok(Self { capacity })
+    }
+
+    // 保存一条消息并清理该频道超出容量的旧消息
+    pub fn add_message(&self, entry: &ChatHistoryEntry) -> Result<(), rusqlite::Error> {
+        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/chat.db")?;
+        let stmt = include_str!("../sql/sqlite/chat_history/add_message.sql");
+        conn.execute(
+            stmt,
+            params![entry.channel, entry.seq as i64, entry.msg, entry.time],
+        )?;
+        let stmt = include_str!("../sql/sqlite/chat_history/trim_channel.sql");
+        conn.execute(stmt, params![entry.channel, self.capacity as i64])?;
+        Ok(())
+    }
+
+    // 读取每个频道最近的消息, 按序号升序
+    pub fn get_recent(&self) -> Result<Vec<ChatHistoryEntry>, rusqlite::Error> {
+        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/chat.db")?;
+        let stmt = include_str!("../sql/sqlite/chat_history/get_recent.sql");
+        let mut stmt = conn.prepare(stmt)?;
+        let mut rows = stmt.query(params![self.capacity as i64])?;
+        let mut entries = Vec::new();
+        while let Some(row) = rows.next()? {
+            entries.push(ChatHistoryEntry {
+                seq: row.get::<_, i64>(0)? as u64,
+                channel: row.get(1)?,
+                msg: row.get(2)?,
+                time: row.get(3)?,
+            });
+        }
+        Ok(entries)
+    }
+}
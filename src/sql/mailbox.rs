@@ -0,0 +1,110 @@
+use rusqlite::{params, Connection};
+
+use crate::DIR_PATH_SQLITE;
+
+/// 离线私信
+/// recipient: 收件玩家
+/// sender: 发件玩家
+/// msg: 消息内容
+/// time: 发送时间(unix秒)
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub id: i64,
+    pub recipient: String,
+    pub sender: String,
+    pub msg: String,
+    pub time: i64,
+}
+
+/// 离线信箱, 保存在chat.db
+#[derive(Debug, Clone)]
+pub struct MailboxSqlite {
+    /// 是否开启离线信箱
+    pub enabled: bool,
+    /// 每个玩家最多保存的离线私信数, 0为不限
+    pub max_per_recipient: usize,
+    /// 离线私信保留天数, 0为不限
+    pub keep_days: u64,
+}
+
+impl MailboxSqlite {
+    /// 初始化, 创建mailbox表并删除过期的离线私信
+    pub async fn init(enabled: bool, max_per_recipient: usize, keep_days: u64) -> Self {
+        tokio::fs::create_dir_all(DIR_PATH_SQLITE).await.err();
+        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/chat.db").unwrap();
+        let stmt = include_str!("../sql/sqlite/mailbox/init.sql");
+        conn.execute_batch(stmt).unwrap();
+        let mailbox = Self {
+            enabled,
+            max_per_recipient,
+            keep_days,
+        };
+        if let Err(err) = mailbox.prune(&conn) {
+            log::error!("删除过期的离线私信失败: {}", err);
+        }
+        mailbox
+    }
+
+    // 删除超过保留天数的离线私信
+    fn prune(&self, conn: &Connection) -> Result<usize, rusqlite::Error> {
+        if self.keep_days == 0 {
+            return Ok(0);
+        }
+        let cutoff = chrono::Local::now().timestamp() - self.keep_days as i64 * 86400;
+        let stmt = include_str!("../sql/sqlite/mailbox/delete_expired.sql");
+        conn.execute(stmt, params![cutoff])
+    }
+
+    // 存入一封离线私信, 收件玩家的信箱已满时不保存并返回false
+    pub fn add_mail(
+        &self,
+        recipient: &str,
+        sender: &str,
+        msg: &str,
+    ) -> Result<bool, rusqlite::Error> {
+        let mut conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/chat.db")?;
+        let tx = conn.transaction()?;
+        self.prune(&tx)?;
+        if self.max_per_recipient > 0 {
+            let stmt = include_str!("../sql/sqlite/mailbox/count_mail.sql");
+            let count: i64 = tx.query_row(stmt, params![recipient], |row| row.get(0))?;
+            if count as usize >= self.max_per_recipient {
+                return Ok(false);
+            }
+        }
+        let stmt = include_str!("../sql/sqlite/mailbox/add_mail.sql");
+        tx.execute(
+            stmt,
+            params![recipient, sender, msg, chrono::Local::now().timestamp()],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    // 获取玩家所有未过期的离线私信
+    pub fn get_mail(&self, recipient: &str) -> Result<Vec<Mail>, rusqlite::Error> {
+        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/chat.db")?;
+        self.prune(&conn)?;
+        let stmt = include_str!("../sql/sqlite/mailbox/get_mail.sql");
+        let mut stmt = conn.prepare(stmt)?;
+        let mut rows = stmt.query(params![recipient])?;
+        let mut mails = Vec::new();
+        while let Some(row) = rows.next()? {
+            mails.push(Mail {
+                id: row.get(0)?,
+                recipient: row.get(1)?,
+                sender: row.get(2)?,
+                msg: row.get(3)?,
+                time: row.get(4)?,
+            });
+        }
+        Ok(mails)
+    }
+
+    // 删除已送达的离线私信
+    pub fn delete_mail(&self, id: i64) -> Result<usize, rusqlite::Error> {
+        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/chat.db")?;
+        let stmt = include_str!("../sql/sqlite/mailbox/delete_mail.sql");
+        conn.execute(stmt, params![id])
+    }
+}
@@ -1,3 +1,7 @@
+pub mod chat_history;
+pub mod mailbox;
+pub mod nbt_revision;
+pub mod player_registry;
 
 
 pub mod multi_economy;
@@ -4,7 +4,10 @@ use log::info;
 use rusqlite::{params, Connection};
 use serde::{Deserialize, Serialize};
 
-use crate::{api::pe::player_api::ResponseMessage, DIR_PATH_SQLITE};
+use crate::{
+    api::pe::{player::normalize_uuid, player_api::ResponseMessage},
+    DIR_PATH_SQLITE,
+};
 
 #[derive(Debug, Clone)]
 pub struct MultiMoneySqlite {
@@ -46,9 +49,14 @@ impl MultiMoneySqlite {
     /// ```
     pub async fn init(&self) {
         tokio::fs::create_dir_all(DIR_PATH_SQLITE).await.err();
-        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/money.db").unwrap();
+        let mut conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/money.db").unwrap();
         let stmt = include_str!("../sql/sqlite/multi_economy/init.sql");
         conn.execute_batch(stmt).unwrap();
+        match migrate_uuids(&mut conn) {
+            Ok(0) => {}
+            Ok(count) => info!("已将{}条玩家经济记录的uuid转换为标准格式", count),
+            Err(err) => log::error!("玩家经济uuid迁移失败: {}", err),
+        }
     }
 
     /// 添加一种经济
@@ -228,7 +236,10 @@ impl MultiMoneySqlite {
             let stmt = include_str!("../sql/sqlite/multi_economy/add_pl_money.sql");
             // let stmt = "INSERT INTO multi_economy (uuid, money, balance) VALUES (?, ?, ?)";
             // conn.execute(stmt, params![player.uuid, player.money, &player.balance])
-            match conn.execute(stmt, params![player.uuid, player.money, player.balance]) {
+            match conn.execute(
+                stmt,
+                params![normalize_uuid(&player.uuid), player.money, player.balance],
+            ) {
                 Ok(_) => ResponseMessage {
                     r#type: "success".to_string(),
                     message: "添加成功".to_string(),
@@ -251,7 +262,9 @@ impl MultiMoneySqlite {
     pub fn get_pl_money(&self, uuid: String, money: String) -> Result<i32, rusqlite::Error> {
         let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/money.db").unwrap();
         let stmt = include_str!("../sql/sqlite/multi_economy/get_money.sql");
-        conn.query_row(stmt, params![uuid, money], |row| row.get(0))
+        conn.query_row(stmt, params![normalize_uuid(&uuid), money], |row| {
+            row.get(0)
+        })
     }
 
     // 更新玩家经济余额
@@ -260,7 +273,10 @@ impl MultiMoneySqlite {
         if self.get_money_key(player.money.clone()) {
             let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/money.db").unwrap();
             let stmt = include_str!("../sql/sqlite/multi_economy/updata_money.sql");
-            conn.execute(stmt, params![player.balance, player.uuid, player.money]);
+            conn.execute(
+                stmt,
+                params![player.balance, normalize_uuid(&player.uuid), player.money],
+            );
             ResponseMessage {
                 r#type: "success".to_string(),
                 message: "修改成功".to_string(),
@@ -353,6 +369,34 @@ impl MultiMoneySqlite {
     }
 }
 
+/// 将旧版本保存的非标准格式uuid(大写、无连字符)转换为标准格式
+/// 同一玩家在同一经济已有标准格式的记录时合并余额, 重复执行不会产生变化
+fn migrate_uuids(conn: &mut Connection) -> Result<usize, rusqlite::Error> {
+    let tx = conn.transaction()?;
+    let mut legacy = Vec::new();
+    {
+        let stmt = include_str!("../sql/sqlite/multi_economy/get_all_pl_money.sql");
+        let mut stmt = tx.prepare(stmt)?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let uuid: String = row.get(0)?;
+            if normalize_uuid(&uuid) != uuid {
+                let money: String = row.get(1)?;
+                let balance: Option<i32> = row.get(2)?;
+                legacy.push((uuid, money, balance.unwrap_or(0)));
+            }
+        }
+    }
+    let delete = include_str!("../sql/sqlite/multi_economy/delete_pl_money.sql");
+    let merge = include_str!("../sql/sqlite/multi_economy/merge_pl_money.sql");
+    for (uuid, money, balance) in &legacy {
+        tx.execute(delete, params![uuid, money])?;
+        tx.execute(merge, params![normalize_uuid(uuid), money, balance])?;
+    }
+    tx.commit()?;
+    Ok(legacy.len())
+}
+
 impl Actor for MultiMoneySqlite {
     type Context = actix::Context<Self>;
 }
@@ -369,3 +413,45 @@ async fn test() {
     let is = multi_money_sqlite.get_money_key("57".to_string());
     println!("{:?}", is);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balance(conn: &Connection, uuid: &str, money: &str) -> i32 {
+        let stmt = include_str!("../sql/sqlite/multi_economy/get_money.sql");
+        conn.query_row(stmt, params![uuid, money], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn migrate_uuids_merges_legacy_rows() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../sql/sqlite/multi_economy/init.sql"))
+            .unwrap();
+        let add = include_str!("../sql/sqlite/multi_economy/add_pl_money.sql");
+        let uuid = "069a79f4-44e9-4726-a5be-fca90e38aaf5";
+        // 旧版本保存的记录
+        conn.execute(
+            add,
+            params!["069A79F444E94726A5BEFCA90E38AAF5", "money", 100],
+        )
+        .unwrap();
+        conn.execute(
+            add,
+            params!["069A79F4-44E9-4726-A5BE-FCA90E38AAF5", "gold", 7],
+        )
+        .unwrap();
+        // 升级后按标准格式新建的零余额记录
+        conn.execute(add, params![uuid, "money", 0]).unwrap();
+
+        assert_eq!(migrate_uuids(&mut conn).unwrap(), 2);
+        assert_eq!(balance(&conn, uuid, "money"), 100);
+        assert_eq!(balance(&conn, uuid, "gold"), 7);
+        let rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM `multi_economy`", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 2);
+        assert_eq!(migrate_uuids(&mut conn).unwrap(), 0);
+    }
+}
@@ -0,0 +1,171 @@
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::DIR_PATH_SQLITE;
+
+/// nbt文件元数据, 上传时解析得到
+/// format: java/bedrock
+/// compression: none/gzip/zlib
+/// data_version: Java版数据版本, 基岩版为None
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NbtMeta {
+    pub format: String,
+    pub compression: String,
+    pub data_version: Option<i32>,
+}
+
+/// 玩家nbt修订
+/// server: 上传的服务端
+/// size: 文件大小(字节)
+/// time: 上传时间(unix秒)
+/// rollback_of: 由回滚产生的修订记录回滚到的修订id
+#[derive(Serialize, Debug, Clone)]
+pub struct NbtRevision {
+    pub id: i64,
+    pub player: String,
+    pub server: String,
+    pub size: u64,
+    #[serde(flatten)]
+    pub meta: NbtMeta,
+    pub time: i64,
+    pub rollback_of: Option<i64>,
+}
+
+/// 玩家nbt修订记录, 保存在player.db
+#[derive(Debug, Clone)]
+pub struct NbtRevisionSqlite {
+    /// 每个玩家保留的修订数, 0为不限
+    pub keep_last: usize,
+    /// 修订保留天数, 0为不限
+    pub keep_days: u64,
+}
+
+impl NbtRevisionSqlite {
+    /// 初始化, 创建nbt_revision表
+    pub async fn init(keep_last: usize, keep_days: u64) -> Self {
+        tokio::fs::create_dir_all(DIR_PATH_SQLITE).await.err();
+        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/player.db").unwrap();
+        let stmt = include_str!("../sql/sqlite/nbt_revision/init.sql");
+        conn.execute_batch(stmt).unwrap();
+        Self {
+            keep_last,
+            keep_days,
+        }
+    }
+
+    // 添加一条修订记录
+    pub fn add_revision(
+        &self,
+        player: &str,
+        server: &str,
+        size: u64,
+        meta: &NbtMeta,
+        rollback_of: Option<i64>,
+    ) -> Result<NbtRevision, rusqlite::Error> {
+        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/player.db")?;
+        let stmt = include_str!("../sql/sqlite/nbt_revision/add_revision.sql");
+        let time = chrono::Local::now().timestamp();
+        conn.execute(
+            stmt,
+            params![
+                player,
+                server,
+                size as i64,
+                meta.format,
+                meta.compression,
+                meta.data_version,
+                time,
+                rollback_of
+            ],
+        )?;
+        Ok(NbtRevision {
+            id: conn.last_insert_rowid(),
+            player: player.to_owned(),
+            server: server.to_owned(),
+            size,
+            meta: meta.clone(),
+            time,
+            rollback_of,
+        })
+    }
+
+    // 获取玩家的所有修订, 按id降序
+    pub fn get_revisions(&self, player: &str) -> Result<Vec<NbtRevision>, rusqlite::Error> {
+        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/player.db")?;
+        let stmt = include_str!("../sql/sqlite/nbt_revision/get_revisions.sql");
+        let mut stmt = conn.prepare(stmt)?;
+        let mut rows = stmt.query(params![player])?;
+        let mut revisions = Vec::new();
+        while let Some(row) = rows.next()? {
+            revisions.push(read_revision(row)?);
+        }
+        Ok(revisions)
+    }
+
+    // 获取玩家的指定修订
+    pub fn get_revision(
+        &self,
+        player: &str,
+        id: i64,
+    ) -> Result<Option<NbtRevision>, rusqlite::Error> {
+        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/player.db")?;
+        let stmt = include_str!("../sql/sqlite/nbt_revision/get_revision.sql");
+        conn.query_row(stmt, params![player, id], read_revision)
+            .optional()
+    }
+
+    // 修改修订记录中的玩家标识, 用于迁移旧版本未规范化的uuid
+    pub fn rename_player(&self, from: &str, to: &str) -> Result<usize, rusqlite::Error> {
+        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/player.db")?;
+        let stmt = include_str!("../sql/sqlite/nbt_revision/rename_player.sql");
+        conn.execute(stmt, params![from, to])
+    }
+
+    // 按保留策略删除玩家的旧修订记录, 返回被删除的修订id, 最新的修订总是保留
+    pub fn prune(&self, player: &str) -> Result<Vec<i64>, rusqlite::Error> {
+        let keep_last = if self.keep_last == 0 {
+            -1
+        } else {
+            self.keep_last as i64
+        };
+        let cutoff = if self.keep_days == 0 {
+            i64::MIN
+        } else {
+            chrono::Local::now().timestamp() - self.keep_days as i64 * 86400
+        };
+        let mut conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/player.db")?;
+        let tx = conn.transaction()?;
+        let mut expired = Vec::new();
+        {
+            let stmt = include_str!("../sql/sqlite/nbt_revision/get_expired.sql");
+            let mut stmt = tx.prepare(stmt)?;
+            let mut rows = stmt.query(params![player, keep_last, cutoff])?;
+            while let Some(row) = rows.next()? {
+                expired.push(row.get(0)?);
+            }
+            let stmt = include_str!("../sql/sqlite/nbt_revision/delete_revision.sql");
+            let mut stmt = tx.prepare(stmt)?;
+            for id in &expired {
+                stmt.execute(params![id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(expired)
+    }
+}
+
+fn read_revision(row: &Row) -> Result<NbtRevision, rusqlite::Error> {
+    Ok(NbtRevision {
+        id: row.get(0)?,
+        player: row.get(1)?,
+        server: row.get(2)?,
+        size: row.get::<_, i64>(3)? as u64,
+        meta: NbtMeta {
+            format: row.get(4)?,
+            compression: row.get(5)?,
+            data_version: row.get(6)?,
+        },
+        time: row.get(7)?,
+        rollback_of: row.get(8)?,
+    })
+}
@@ -0,0 +1,84 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::DIR_PATH_SQLITE;
+
+/// 玩家最后已知状态
+/// server: 玩家所在或最后所在的服务端
+/// online: 是否在线
+/// last_seen: 最后一次加入、心跳或离开的时间(unix秒)
+#[derive(Serialize, Debug, Clone)]
+pub struct PlayerRecord {
+    pub uuid: String,
+    pub name: String,
+    pub server: String,
+    pub online: bool,
+    pub last_seen: i64,
+}
+
+/// 玩家列表持久化, 保存在player.db
+#[derive(Debug, Clone)]
+pub struct PlayerRegistrySqlite;
+
+impl PlayerRegistrySqlite {
+    /// 初始化, 创建player.db
+    /// 启动时内存中没有在线玩家, 将所有记录标记为离线, 等待游戏服重连后同步
+    pub fn init() -> Result<Self, rusqlite::Error> {
+        std::fs::create_dir_all(DIR_PATH_SQLITE).ok();
+        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/player.db")?;
+        let stmt = include_str!("../sql/sqlite/player_registry/init.sql");
+        conn.execute_batch(stmt)?;
+        let stmt = include_str!("../sql/sqlite/player_registry/set_all_offline.sql");
+        conn.execute(stmt, params![])?;
+        Ok(Self)
+    }
+
+    // 在同一事务中保存多个玩家的状态
+    pub fn save(&self, records: &[PlayerRecord]) -> Result<(), rusqlite::Error> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/player.db")?;
+        let tx = conn.transaction()?;
+        {
+            let stmt = include_str!("../sql/sqlite/player_registry/save_player.sql");
+            let mut stmt = tx.prepare(stmt)?;
+            for record in records {
+                stmt.execute(params![
+                    record.uuid,
+                    record.name,
+                    record.server,
+                    record.online,
+                    record.last_seen
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    // 按uuid查询玩家
+    pub fn get_by_uuid(&self, uuid: &str) -> Result<Option<PlayerRecord>, rusqlite::Error> {
+        let stmt = include_str!("../sql/sqlite/player_registry/get_by_uuid.sql");
+        self.query_one(stmt, uuid)
+    }
+
+    // 按玩家名查询玩家, 同名时返回最后在线的玩家
+    pub fn get_by_name(&self, name: &str) -> Result<Option<PlayerRecord>, rusqlite::Error> {
+        let stmt = include_str!("../sql/sqlite/player_registry/get_by_name.sql");
+        self.query_one(stmt, name)
+    }
+
+    fn query_one(&self, stmt: &str, key: &str) -> Result<Option<PlayerRecord>, rusqlite::Error> {
+        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/player.db")?;
+        conn.query_row(stmt, params![key], |row| {
+            Ok(PlayerRecord {
+                uuid: row.get(0)?,
+                name: row.get(1)?,
+                server: row.get(2)?,
+                online: row.get(3)?,
+                last_seen: row.get(4)?,
+            })
+        })
+        .optional()
+    }
+}
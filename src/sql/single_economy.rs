@@ -1,7 +1,7 @@
 use actix::Actor;
 use rusqlite::{params, Connection};
 
-use crate::DIR_PATH_SQLITE;
+use crate::{api::pe::player::normalize_uuid, DIR_PATH_SQLITE};
 
 struct SinglePlayerMoney {
     uuid: String,
@@ -16,10 +16,15 @@ pub struct SingleMoneySqlite {
 impl SingleMoneySqlite {
     pub async fn init(debt_limit: i32) -> Self {
         tokio::fs::create_dir_all(DIR_PATH_SQLITE).await.err();
-        let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/money.db").unwrap();
+        let mut conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/money.db").unwrap();
         // 初始化创建单经济表
         let stmt = include_str!("../sql/sqlite/single_economy/init.sql");
         conn.execute_batch(stmt).unwrap();
+        match migrate_uuids(&mut conn) {
+            Ok(0) => {}
+            Ok(count) => log::info!("已将{}条玩家经济记录的uuid转换为标准格式", count),
+            Err(err) => log::error!("玩家经济uuid迁移失败: {}", err),
+        }
         Self {
             debt_limit: -debt_limit,
         }
@@ -34,21 +39,21 @@ impl SingleMoneySqlite {
     pub fn init_pl_money(&self, player: SinglePlayerMoney) -> Result<usize, rusqlite::Error> {
         let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/money.db").unwrap();
         let stmt = include_str!("../sql/sqlite/single_economy/add_pl.sql");
-        conn.execute(stmt, params![player.uuid, &player.balance])
+        conn.execute(stmt, params![normalize_uuid(&player.uuid), &player.balance])
     }
 
     // 获取玩家经济
     pub fn get_pl_money(&self, uuid: String) -> Result<i32, rusqlite::Error> {
         let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/money.db").unwrap();
         let stmt = include_str!("../sql/sqlite/single_economy/get_money.sql");
-        conn.query_row(stmt, params![uuid], |row| row.get(0))
+        conn.query_row(stmt, params![normalize_uuid(&uuid)], |row| row.get(0))
     }
 
     // 更新玩家经济
     pub fn update_pl_money(&self, player: SinglePlayerMoney) -> Result<usize, rusqlite::Error> {
         let conn = Connection::open(DIR_PATH_SQLITE.to_owned() + "/money.db").unwrap();
         let stmt = include_str!("../sql/sqlite/single_economy/updata_money.sql");
-        conn.execute(stmt, params![player.balance, player.uuid])
+        conn.execute(stmt, params![player.balance, normalize_uuid(&player.uuid)])
     }
 
     // 增加玩家经济
@@ -102,6 +107,64 @@ impl SingleMoneySqlite {
     }
 }
 
+/// 将旧版本保存的非标准格式uuid(大写、无连字符)转换为标准格式
+/// 已有标准格式的记录时合并余额, 重复执行不会产生变化
+fn migrate_uuids(conn: &mut Connection) -> Result<usize, rusqlite::Error> {
+    let tx = conn.transaction()?;
+    let mut legacy = Vec::new();
+    {
+        let stmt = include_str!("../sql/sqlite/single_economy/get_all_pl.sql");
+        let mut stmt = tx.prepare(stmt)?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let uuid: String = row.get(0)?;
+            if normalize_uuid(&uuid) != uuid {
+                let balance: Option<i32> = row.get(1)?;
+                legacy.push((uuid, balance.unwrap_or(0)));
+            }
+        }
+    }
+    let delete = include_str!("../sql/sqlite/single_economy/delete_pl.sql");
+    let merge = include_str!("../sql/sqlite/single_economy/merge_pl.sql");
+    for (uuid, balance) in &legacy {
+        tx.execute(delete, params![uuid])?;
+        tx.execute(merge, params![normalize_uuid(uuid), balance])?;
+    }
+    tx.commit()?;
+    Ok(legacy.len())
+}
+
 impl Actor for SingleMoneySqlite {
     type Context = actix::Context<Self>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_uuids_merges_legacy_rows() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../sql/sqlite/single_economy/init.sql"))
+            .unwrap();
+        let add = include_str!("../sql/sqlite/single_economy/add_pl.sql");
+        let uuid = "069a79f4-44e9-4726-a5be-fca90e38aaf5";
+        // 旧版本保存的记录和升级后新建的零余额记录
+        conn.execute(add, params!["069A79F444E94726A5BEFCA90E38AAF5", 100])
+            .unwrap();
+        conn.execute(add, params![uuid, 0]).unwrap();
+        // 另一个玩家的旧记录, 没有冲突
+        conn.execute(add, params!["853C80EF3C3749FDAA49938B674ADAE6", 5])
+            .unwrap();
+
+        assert_eq!(migrate_uuids(&mut conn).unwrap(), 2);
+        let get = include_str!("../sql/sqlite/single_economy/get_money.sql");
+        let balance = |uuid: &str| -> i32 {
+            conn.query_row(get, params![uuid], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(balance(uuid), 100);
+        assert_eq!(balance("853c80ef-3c37-49fd-aa49-938b674adae6"), 5);
+        assert_eq!(migrate_uuids(&mut conn).unwrap(), 0);
+    }
+}
@@ -0,0 +1 @@
+INSERT OR REPLACE INTO `chat_history` (`channel`, `seq`, `msg`, `time`) VALUES (?1, ?2, ?3, ?4);
@@ -0,0 +1,7 @@
+SELECT `seq`, `channel`, `msg`, `time` FROM (
+    SELECT `seq`, `channel`, `msg`, `time`,
+        ROW_NUMBER() OVER (PARTITION BY `channel` ORDER BY `seq` DESC) AS `rank`
+    FROM `chat_history`
+)
+WHERE `rank` <= ?1
+ORDER BY `channel`, `seq` ASC;
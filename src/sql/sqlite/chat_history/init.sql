@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS `chat_history` (
+    `channel` TEXT NOT NULL,
+    `seq` INTEGER NOT NULL,
+    `msg` TEXT NOT NULL,
+    `time` INTEGER NOT NULL,
+    PRIMARY KEY (`channel`, `seq`)
+);
@@ -0,0 +1,5 @@
+DELETE FROM `chat_history`
+WHERE `channel` = ?1
+  AND `seq` NOT IN (
+    SELECT `seq` FROM `chat_history` WHERE `channel` = ?1 ORDER BY `seq` DESC LIMIT ?2
+  );
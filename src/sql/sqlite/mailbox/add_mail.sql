@@ -0,0 +1 @@
+INSERT INTO `mailbox` (`recipient`, `sender`, `msg`, `time`) VALUES (?1, ?2, ?3, ?4);
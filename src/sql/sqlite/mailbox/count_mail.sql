@@ -0,0 +1 @@
+SELECT COUNT(*) FROM `mailbox` WHERE `recipient` = ?1;
@@ -0,0 +1 @@
+DELETE FROM `mailbox` WHERE `time` < ?1;
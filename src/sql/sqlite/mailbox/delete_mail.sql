@@ -0,0 +1 @@
+DELETE FROM `mailbox` WHERE `id` = ?1;
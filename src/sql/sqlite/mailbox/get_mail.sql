@@ -0,0 +1 @@
+SELECT `id`, `recipient`, `sender`, `msg`, `time` FROM `mailbox` WHERE `recipient` = ?1 ORDER BY `id` ASC;
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS `mailbox` (
+    `id` INTEGER PRIMARY KEY AUTOINCREMENT,
+    `recipient` TEXT NOT NULL,
+    `sender` TEXT NOT NULL,
+    `msg` TEXT NOT NULL,
+    `time` INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS `mailbox_recipient` ON `mailbox` (`recipient`);
+
+CREATE INDEX IF NOT EXISTS `mailbox_time` ON `mailbox` (`time`);
@@ -0,0 +1 @@
+DELETE FROM `multi_economy` WHERE `uuid` = ?1 AND `money` = ?2;
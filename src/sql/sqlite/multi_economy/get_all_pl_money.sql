@@ -0,0 +1 @@
+SELECT `uuid`, `money`, `balance` FROM `multi_economy`;
@@ -0,0 +1,2 @@
+INSERT INTO `multi_economy` (`uuid`, `money`, `balance`) VALUES (?1, ?2, ?3)
+ON CONFLICT (`uuid`, `money`) DO UPDATE SET `balance` = COALESCE(`balance`, 0) + COALESCE(excluded.`balance`, 0);
@@ -0,0 +1,2 @@
+INSERT INTO `nbt_revision` (`player`, `server`, `size`, `format`, `compression`, `data_version`, `time`, `rollback_of`)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);
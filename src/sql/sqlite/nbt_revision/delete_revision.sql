@@ -0,0 +1 @@
+DELETE FROM `nbt_revision` WHERE `id` = ?1;
@@ -0,0 +1,11 @@
+SELECT `id` FROM `nbt_revision`
+WHERE `player` = ?1
+  AND `id` NOT IN (
+    SELECT `id` FROM `nbt_revision` WHERE `player` = ?1 ORDER BY `id` DESC LIMIT 1
+  )
+  AND (
+    `id` NOT IN (
+      SELECT `id` FROM `nbt_revision` WHERE `player` = ?1 ORDER BY `id` DESC LIMIT ?2
+    )
+    OR `time` < ?3
+  );
@@ -0,0 +1,2 @@
+SELECT `id`, `player`, `server`, `size`, `format`, `compression`, `data_version`, `time`, `rollback_of` FROM `nbt_revision`
+WHERE `player` = ?1 AND `id` = ?2;
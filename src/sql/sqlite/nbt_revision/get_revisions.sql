@@ -0,0 +1,3 @@
+SELECT `id`, `player`, `server`, `size`, `format`, `compression`, `data_version`, `time`, `rollback_of` FROM `nbt_revision`
+WHERE `player` = ?1
+ORDER BY `id` DESC;
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS `nbt_revision` (
+    `id` INTEGER PRIMARY KEY AUTOINCREMENT,
+    `player` TEXT NOT NULL,
+    `server` TEXT NOT NULL,
+    `size` INTEGER NOT NULL,
+    `format` TEXT NOT NULL,
+    `compression` TEXT NOT NULL,
+    `data_version` INTEGER,
+    `time` INTEGER NOT NULL,
+    `rollback_of` INTEGER
+);
+CREATE INDEX IF NOT EXISTS `nbt_revision_player` ON `nbt_revision` (`player`, `id`);
@@ -0,0 +1 @@
+UPDATE `nbt_revision` SET `player` = ?2 WHERE `player` = ?1;
@@ -0,0 +1,4 @@
+SELECT `uuid`, `name`, `server`, `online`, `last_seen` FROM `player_registry`
+WHERE `name` = ?1
+ORDER BY `last_seen` DESC
+LIMIT 1;
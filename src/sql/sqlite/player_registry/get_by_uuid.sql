@@ -0,0 +1 @@
+SELECT `uuid`, `name`, `server`, `online`, `last_seen` FROM `player_registry` WHERE `uuid` = ?1;
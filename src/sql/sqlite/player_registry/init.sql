@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS `player_registry` (
+    `uuid` TEXT PRIMARY KEY NOT NULL,
+    `name` TEXT NOT NULL,
+    `server` TEXT NOT NULL,
+    `online` INTEGER NOT NULL,
+    `last_seen` INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS `player_registry_name` ON `player_registry` (`name`);
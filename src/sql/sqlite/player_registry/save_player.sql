@@ -0,0 +1,6 @@
+INSERT INTO `player_registry` (`uuid`, `name`, `server`, `online`, `last_seen`) VALUES (?1, ?2, ?3, ?4, ?5)
+ON CONFLICT (`uuid`) DO UPDATE SET
+    `name` = excluded.`name`,
+    `server` = excluded.`server`,
+    `online` = excluded.`online`,
+    `last_seen` = excluded.`last_seen`;
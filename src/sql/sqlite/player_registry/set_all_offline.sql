@@ -0,0 +1 @@
+UPDATE `player_registry` SET `online` = 0 WHERE `online` = 1;
@@ -0,0 +1 @@
+DELETE FROM `single_economy` WHERE `uuid` = ?1;
@@ -0,0 +1 @@
+SELECT `uuid`, `balance` FROM `single_economy`;
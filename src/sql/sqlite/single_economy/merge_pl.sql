@@ -0,0 +1,2 @@
+INSERT INTO `single_economy` (`uuid`, `balance`) VALUES (?1, ?2)
+ON CONFLICT (`uuid`) DO UPDATE SET `balance` = COALESCE(`balance`, 0) + COALESCE(excluded.`balance`, 0);